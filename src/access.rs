@@ -0,0 +1,228 @@
+//! The access log: one line per request, written to a file (or
+//! stdout) in either a Common Log Format-like layout or JSON.
+//!
+//! Common:
+//!
+//! ```text
+//! 127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "/phlog" "" DIR 1024 3.172
+//! ```
+//!
+//! The fields are peer address, timestamp, selector, query, response
+//! kind, bytes sent, and duration in milliseconds.
+
+use crate::{date::Date, log::LogFile, Result};
+use std::{
+    fmt,
+    io::{self, Write},
+    net::SocketAddr,
+    sync::Mutex,
+    time::Duration,
+};
+
+/// Layout of access log lines.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Format {
+    /// Space-separated, like a web server's Common Log Format.
+    Common,
+    /// One JSON object per line.
+    Json,
+}
+
+impl Format {
+    /// Parse a format name: `common` or `json`.
+    pub fn from(name: &str) -> Option<Format> {
+        match name {
+            "common" | "clf" => Some(Format::Common),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+}
+
+/// A single request, as it appears in the access log.
+#[derive(Debug)]
+pub struct Entry<'a> {
+    /// When the request was received.
+    pub time: Date,
+    /// Address of the client, if known.
+    pub peer: Option<SocketAddr>,
+    /// Selector the client sent.
+    pub selector: &'a str,
+    /// Query the client sent, if any.
+    pub query: &'a str,
+    /// Kind of response, e.g. `DIR` or `NOTFOUND`.
+    pub kind: &'a str,
+    /// Number of bytes written to the client.
+    pub bytes: u64,
+    /// How long it took to respond.
+    pub duration: Duration,
+}
+
+impl Entry<'_> {
+    /// Format as a Common Log Format-like line.
+    pub fn common(&self) -> String {
+        format!(
+            "{} - - [{}] \"{}\" \"{}\" {} {} {:.3}",
+            self.peer_str(),
+            self.time.clf(),
+            escape(self.selector),
+            escape(self.query),
+            self.kind,
+            self.bytes,
+            self.millis()
+        )
+    }
+
+    /// Format as a JSON object.
+    pub fn json(&self) -> String {
+        format!(
+            "{{\"time\":\"{}\",\"peer\":\"{}\",\"selector\":\"{}\",\"query\":\"{}\",\"kind\":\"{}\",\"bytes\":{},\"duration_ms\":{:.3}}}",
+            self.time.rfc3339(),
+            self.peer_str(),
+            escape(self.selector),
+            escape(self.query),
+            self.kind,
+            self.bytes,
+            self.millis()
+        )
+    }
+
+    fn peer_str(&self) -> String {
+        match self.peer {
+            Some(addr) => addr.ip().to_canonical().to_string(),
+            None => "-".into(),
+        }
+    }
+
+    fn millis(&self) -> f64 {
+        self.duration.as_secs_f64() * 1000.0
+    }
+}
+
+/// Where access log entries go.
+pub struct AccessLog {
+    path: String,
+    /// None for stdout.
+    file: Option<LogFile>,
+    format: Format,
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl fmt::Debug for AccessLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AccessLog({}, {:?})", self.path, self.format)
+    }
+}
+
+impl AccessLog {
+    /// Open the access log at `path`, appending to it. A path of `-`
+    /// means stdout.
+    pub fn open(path: &str, format: Format) -> Result<AccessLog> {
+        if path == "-" {
+            return Ok(AccessLog {
+                path: path.into(),
+                file: None,
+                format,
+                out: Mutex::new(Box::new(io::stdout())),
+            });
+        }
+        AccessLog::to_log_file(LogFile::new(path)?, format)
+    }
+
+    /// Append entries to `file`.
+    pub fn to_log_file(file: LogFile, format: Format) -> Result<AccessLog> {
+        Ok(AccessLog {
+            path: file.path().into(),
+            out: Mutex::new(Box::new(file.open()?)),
+            file: Some(file),
+            format,
+        })
+    }
+
+    /// The file we're logging to, unless it's stdout.
+    pub fn log_file(&self) -> Option<&LogFile> {
+        self.file.as_ref()
+    }
+
+    /// Path to the log file, or `-` for stdout.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Layout of log lines.
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Close and re-open the log file, e.g. after logrotate moves it.
+    pub fn reopen(&self) -> Result<()> {
+        if let Some(file) = &self.file {
+            *self.out.lock().unwrap() = Box::new(file.open()?);
+        }
+        Ok(())
+    }
+
+    /// Write an entry to the log.
+    pub fn log(&self, entry: &Entry<'_>) -> Result<()> {
+        let line = match self.format {
+            Format::Common => entry.common(),
+            Format::Json => entry.json(),
+        };
+        let mut out = self.out.lock().unwrap();
+        writeln!(out, "{}", line)?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+/// Escape a string for use inside double quotes, JSON-style.
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry() -> Entry<'static> {
+        Entry {
+            time: Date::from_unix(971_186_136),
+            peer: Some("127.0.0.1:4242".parse().unwrap()),
+            selector: "/say \"hi\"",
+            query: "a\tb",
+            kind: "MAP",
+            bytes: 1024,
+            duration: Duration::from_micros(3172),
+        }
+    }
+
+    #[test]
+    fn test_common_format() {
+        assert_eq!(
+            entry().common(),
+            r#"127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "/say \"hi\"" "a\tb" MAP 1024 3.172"#
+        );
+    }
+
+    #[test]
+    fn test_json_format() {
+        let mut e = entry();
+        e.peer = None;
+        assert_eq!(
+            e.json(),
+            r#"{"time":"2000-10-10T13:55:36Z","peer":"-","selector":"/say \"hi\"","query":"a\tb","kind":"MAP","bytes":1024,"duration_ms":3.172}"#
+        );
+    }
+}
@@ -0,0 +1,276 @@
+//! Access control by client IP. Networks can be blocked everywhere,
+//! or parts of the site limited to certain networks:
+//!
+//! ```text
+//! # nobody from here, anywhere
+//! deny = 203.0.113.0/24
+//! # /private only from the LAN
+//! allow = /private 192.168.0.0/16 10.0.0.0/8 ::1
+//! # scripts only from localhost
+//! allow-scripts = 127.0.0.1 ::1
+//! ```
+
+use crate::Result;
+use std::{fmt, net::IpAddr};
+
+/// A network in CIDR notation, like `10.0.0.0/8`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    bits: u8,
+}
+
+impl Cidr {
+    /// Parse `ADDR/BITS`, or a single address.
+    pub fn parse(s: &str) -> Result<Cidr> {
+        let (addr, bits) = match s.find('/') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| format!("bad network: {}", s))?;
+        let max: u8 = if addr.is_ipv4() { 32 } else { 128 };
+        let bits = match bits {
+            Some(bits) => match bits.parse() {
+                Ok(bits) if bits <= max => bits,
+                _ => return Err(format!("bad network: {}", s).into()),
+            },
+            None => max,
+        };
+        Ok(Cidr {
+            addr: addr.to_canonical(),
+            bits: if addr.to_canonical().is_ipv4() && !addr.is_ipv4() {
+                bits.saturating_sub(96)
+            } else {
+                bits
+            },
+        })
+    }
+
+    /// Is this address inside the network?
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_eq(u32::from(net) as u128, u32::from(ip) as u128, 32, self.bits)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_eq(u128::from(net), u128::from(ip), 128, self.bits)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.bits)
+    }
+}
+
+/// Do the first `bits` bits of two `width`-bit numbers match?
+fn prefix_eq(a: u128, b: u128, width: u8, bits: u8) -> bool {
+    if bits == 0 {
+        return true;
+    }
+    let shift = width - bits;
+    a >> shift == b >> shift
+}
+
+/// What part of the site a rule covers.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    /// Selectors starting with this path, e.g. `/private`.
+    Prefix(String),
+    /// Executable `.gph` files, wherever they are.
+    Scripts,
+}
+
+/// A single `allow` or `deny` line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    /// True for `allow`, false for `deny`.
+    pub allow: bool,
+    /// What the rule covers.
+    pub target: Target,
+    /// Networks the rule applies to.
+    pub nets: Vec<Cidr>,
+}
+
+impl Rule {
+    /// Parse the value of an `allow` or `deny` setting:
+    /// `[/PREFIX] NETWORK...`. Without a prefix, the rule covers the
+    /// whole site.
+    pub fn parse(allow: bool, val: &str) -> Result<Rule> {
+        let mut words = val.split_whitespace().peekable();
+        let prefix = match words.peek() {
+            Some(word) if word.starts_with('/') => normalize(words.next().unwrap_or("/")),
+            _ => "".into(),
+        };
+        Rule::new(allow, Target::Prefix(prefix), words)
+    }
+
+    /// Parse the value of an `allow-scripts` or `deny-scripts`
+    /// setting: `NETWORK...`.
+    pub fn parse_scripts(allow: bool, val: &str) -> Result<Rule> {
+        Rule::new(allow, Target::Scripts, val.split_whitespace())
+    }
+
+    fn new<'a>(allow: bool, target: Target, nets: impl Iterator<Item = &'a str>) -> Result<Rule> {
+        let nets = nets.map(Cidr::parse).collect::<Result<Vec<_>>>()?;
+        if nets.is_empty() {
+            return Err("expected at least one network".into());
+        }
+        Ok(Rule {
+            allow,
+            target,
+            nets,
+        })
+    }
+
+    fn contains(&self, peer: Option<IpAddr>) -> bool {
+        peer.is_some_and(|ip| self.nets.iter().any(|net| net.contains(ip)))
+    }
+}
+
+/// All the access rules for a site.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Acl {
+    rules: Vec<Rule>,
+}
+
+impl Acl {
+    /// Add a rule.
+    pub fn push(&mut self, rule: Rule) {
+        self.rules.push(rule);
+    }
+
+    /// Are there any rules at all?
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// May `peer` request `selector`?
+    ///
+    /// Any matching `deny` rule that includes the peer refuses it. If
+    /// `allow` rules cover the selector, the peer must be in the one
+    /// with the longest prefix. A peer with an unknown address is in
+    /// no network.
+    pub fn allows(&self, selector: &str, peer: Option<IpAddr>) -> bool {
+        let selector = normalize(selector);
+        self.check(peer, |target| match target {
+            Target::Prefix(prefix) => prefix_len(prefix, &selector),
+            Target::Scripts => None,
+        })
+    }
+
+    /// May `peer` run an executable `.gph` file?
+    pub fn allows_script(&self, peer: Option<IpAddr>) -> bool {
+        self.check(peer, |target| match target {
+            Target::Scripts => Some(0),
+            Target::Prefix(_) => None,
+        })
+    }
+
+    /// `covers` returns how specific a rule's target is, or None if it
+    /// doesn't apply.
+    fn check(&self, peer: Option<IpAddr>, covers: impl Fn(&Target) -> Option<usize>) -> bool {
+        let mut allow: Option<(usize, bool)> = None;
+        for rule in &self.rules {
+            let len = match covers(&rule.target) {
+                Some(len) => len,
+                None => continue,
+            };
+            if !rule.allow {
+                if rule.contains(peer) {
+                    return false;
+                }
+            } else {
+                allow = match allow {
+                    Some((best, ok)) if best > len => Some((best, ok)),
+                    Some((best, ok)) if best == len => Some((best, ok || rule.contains(peer))),
+                    _ => Some((len, rule.contains(peer))),
+                };
+            }
+        }
+        allow.is_none_or(|(_, ok)| ok)
+    }
+}
+
+/// `/private/` => `/private`, `private//./x` => `/private/x`, `/` => ``
+///
+/// Selectors that point at the same file should match the same rules.
+fn normalize(selector: &str) -> String {
+    selector
+        .replace("..", ".")
+        .split('/')
+        .filter(|part| !part.is_empty() && *part != ".")
+        .fold(String::new(), |mut path, part| {
+            path.push('/');
+            path.push_str(part);
+            path
+        })
+}
+
+/// If `prefix` covers `selector`, how long is it? `/priv` covers
+/// `/priv` and `/priv/x` but not `/private`.
+fn prefix_len(prefix: &str, selector: &str) -> Option<usize> {
+    let rest = selector.strip_prefix(prefix)?;
+    if rest.is_empty() || rest.starts_with('/') {
+        Some(prefix.len())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        s.parse().ok()
+    }
+
+    #[test]
+    fn test_cidr() {
+        let net = Cidr::parse("10.1.0.0/16").unwrap();
+        assert!(net.contains("10.1.2.3".parse().unwrap()));
+        assert!(net.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!net.contains("10.2.0.1".parse().unwrap()));
+        assert!(!net.contains("::1".parse().unwrap()));
+        let net = Cidr::parse("2001:db8::/32").unwrap();
+        assert!(net.contains("2001:db8:1::1".parse().unwrap()));
+        assert!(!net.contains("2001:db9::1".parse().unwrap()));
+        assert!(Cidr::parse("0.0.0.0/0")
+            .unwrap()
+            .contains("8.8.8.8".parse().unwrap()));
+        assert_eq!(Cidr::parse("::1").unwrap().to_string(), "::1/128");
+        assert!(Cidr::parse("10.0.0.0/33").is_err());
+        assert!(Cidr::parse("nope").is_err());
+    }
+
+    #[test]
+    fn test_acl() {
+        let mut acl = Acl::default();
+        acl.push(Rule::parse(false, "203.0.113.0/24").unwrap());
+        acl.push(Rule::parse(true, "/private 10.0.0.0/8").unwrap());
+        acl.push(Rule::parse(true, "/private/open 0.0.0.0/0").unwrap());
+        acl.push(Rule::parse_scripts(true, "127.0.0.1").unwrap());
+
+        assert!(acl.allows("/", ip("8.8.8.8")));
+        assert!(!acl.allows("/", ip("203.0.113.9")));
+        assert!(!acl.allows("/private/open", ip("203.0.113.9")));
+        assert!(acl.allows("/private/x", ip("10.0.0.1")));
+        assert!(acl.allows("private/x", ip("10.0.0.1")));
+        assert!(!acl.allows("//private/x", ip("8.8.8.8")));
+        assert!(!acl.allows("/./private", ip("8.8.8.8")));
+        assert!(!acl.allows("/../private", ip("8.8.8.8")));
+        assert!(!acl.allows("/private/x", ip("8.8.8.8")));
+        assert!(!acl.allows("/private", None));
+        assert!(acl.allows("/privateer", ip("8.8.8.8")));
+        assert!(acl.allows("/private/open/y", ip("8.8.8.8")));
+        assert!(acl.allows_script(ip("127.0.0.1")));
+        assert!(!acl.allows_script(ip("10.0.0.1")));
+
+        assert!(Rule::parse(true, "/private").is_err());
+        assert!(Rule::parse(true, "/private 10.0.0.0/99").is_err());
+    }
+}
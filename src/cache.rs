@@ -0,0 +1,213 @@
+//! Rendered menus, kept in memory so big directories don't have to be
+//! read, sorted, and sniffed for every client.
+//!
+//! Each entry remembers the modification time and size of the files
+//! it was built from. If any of them change, the entry is thrown away
+//! the next time it's looked up, or as soon as the watcher tells us
+//! about it. When the cache grows past its size limit, the least
+//! recently used entries go first.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+/// What a file looked like when an entry was built from it. `None`
+/// means it didn't exist.
+type Stamp = (PathBuf, Option<(SystemTime, u64)>);
+
+/// A single cached response.
+#[derive(Debug)]
+struct Entry {
+    body: Arc<[u8]>,
+    deps: Vec<Stamp>,
+    /// When this entry was last used, on the cache's own clock.
+    used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    entries: HashMap<String, Entry>,
+    /// Total bytes of all cached bodies.
+    size: usize,
+    /// Ticks on every lookup, to find the least recently used entry.
+    clock: u64,
+}
+
+/// Shared cache of rendered responses, keyed by file path.
+#[derive(Debug, Default)]
+pub struct Cache {
+    inner: Mutex<Inner>,
+}
+
+/// The files a response is about to be built from. Take it before
+/// building the response, so changes made while it's being built
+/// invalidate the entry rather than being missed.
+#[derive(Debug)]
+pub struct Deps(Vec<Stamp>);
+
+impl Deps {
+    /// Note the current state of `paths`.
+    pub fn of<P: AsRef<Path>>(paths: &[P]) -> Deps {
+        Deps(paths.iter().map(|path| stamp(path.as_ref())).collect())
+    }
+}
+
+impl Cache {
+    /// The cached response for `key`, if there is one and none of the
+    /// files it was built from have changed.
+    pub fn get(&self, key: &str) -> Option<Arc<[u8]>> {
+        let deps = {
+            let inner = self.inner.lock().unwrap();
+            inner.entries.get(key)?.deps.clone()
+        };
+        // Check the files without holding the lock.
+        let fresh = deps.iter().all(|dep| stamp(&dep.0) == *dep);
+
+        let mut inner = self.inner.lock().unwrap();
+        if !fresh {
+            inner.remove(key);
+            return None;
+        }
+        inner.clock += 1;
+        let now = inner.clock;
+        let entry = inner.entries.get_mut(key)?;
+        entry.used = now;
+        Some(Arc::clone(&entry.body))
+    }
+
+    /// Cache `body` under `key`, evicting old entries to keep the
+    /// total under `max` bytes. Bodies bigger than `max` aren't kept.
+    pub fn insert(&self, key: &str, body: &[u8], deps: Deps, max: usize) {
+        if body.len() > max {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.remove(key);
+        while inner.size + body.len() > max {
+            let oldest = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.used)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(oldest) => inner.remove(&oldest),
+                None => break,
+            }
+        }
+        inner.clock += 1;
+        let entry = Entry {
+            body: body.into(),
+            deps: deps.0,
+            used: inner.clock,
+        };
+        inner.size += body.len();
+        inner.entries.insert(key.into(), entry);
+    }
+
+    /// Forget responses built from `path`, or from the directory it's
+    /// in: adding, removing, or changing a file can change the listing
+    /// of its directory.
+    pub fn invalidate(&self, path: &Path) {
+        let parent = path.parent();
+        let mut inner = self.inner.lock().unwrap();
+        let stale: Vec<String> = inner
+            .entries
+            .iter()
+            .filter(|(_, entry)| {
+                entry
+                    .deps
+                    .iter()
+                    .any(|(dep, _)| dep == path || Some(dep.as_path()) == parent)
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in stale {
+            inner.remove(&key);
+        }
+    }
+
+    /// Forget everything.
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.entries.clear();
+        inner.size = 0;
+    }
+
+    /// Number of cached responses.
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+
+    /// Is the cache empty?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Total size of the cached responses, in bytes.
+    pub fn size(&self) -> usize {
+        self.inner.lock().unwrap().size
+    }
+}
+
+impl Inner {
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.size -= entry.body.len();
+        }
+    }
+}
+
+/// Record the modification time and size of a file.
+fn stamp(path: &Path) -> Stamp {
+    let meta = fs::metadata(path)
+        .ok()
+        .and_then(|meta| Some((meta.modified().ok()?, meta.len())));
+    (path.into(), meta)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn test_cache() {
+        let tmp = TempDir::new("cache");
+        let dir = tmp.path();
+        let (a, b) = (tmp.write("a.gph", "one"), tmp.write("b.gph", "two"));
+
+        let cache = Cache::default();
+        cache.insert("a", b"AAAA", Deps::of(&[&a]), 10);
+        cache.insert("b", b"BBBB", Deps::of(&[&b, &dir.join("missing")]), 10);
+        assert_eq!(&*cache.get("a").unwrap(), b"AAAA");
+        assert_eq!(cache.size(), 8);
+
+        // "b" is the least recently used, so it makes room for "c".
+        cache.insert("c", b"CCCC", Deps::of(&[&a]), 10);
+        assert!(cache.get("b").is_none());
+        assert_eq!(cache.len(), 2);
+        cache.insert("big", b"too big to fit", Deps::of(&[&a]), 10);
+        assert!(cache.get("big").is_none());
+
+        // Changing a file invalidates everything built from it.
+        fs::write(&a, "changed").unwrap();
+        assert!(cache.get("a").is_none());
+        assert!(cache.get("c").is_none());
+        assert!(cache.is_empty());
+        assert_eq!(cache.size(), 0);
+
+        cache.insert("b", b"BBBB", Deps::of(&[&dir.join("missing")]), 10);
+        assert!(cache.get("b").is_some());
+        fs::write(dir.join("missing"), "").unwrap();
+        assert!(cache.get("b").is_none());
+
+        // Files inside a directory affect its listing.
+        cache.insert("dir", b"DDDD", Deps::of(&[&dir]), 10);
+        cache.invalidate(&dir.join("other.txt"));
+        assert!(cache.is_empty());
+    }
+}
@@ -0,0 +1,540 @@
+//! Server configuration. Settings come from an optional config file
+//! and the command line, with command line flags taking precedence.
+//!
+//! The config file is a list of `key = value` lines:
+//!
+//! ```text
+//! # phd.conf
+//! root = /srv/gopher
+//! host = gopher.example.com
+//! port = 70
+//! ignore = *.bak
+//! type.md = 0
+//! ```
+//!
+//! Sending phd `SIGHUP` re-reads the file and applies the new settings
+//! to new connections.
+
+use crate::{
+    access,
+    acl::{Acl, Rule},
+    gopher,
+    log::Level,
+    server::{Backend, Overflow},
+    Result,
+};
+use std::{collections::HashMap, fs, net::SocketAddr, time::Duration};
+
+/// Socket address to bind to if none is given.
+pub const DEFAULT_BIND: &str = "[::]:7070";
+/// Hostname used in links if none is given.
+pub const DEFAULT_HOST: &str = "127.0.0.1";
+/// Port used in links if none is given.
+pub const DEFAULT_PORT: u16 = 7070;
+/// Seconds to wait for in-flight requests when shutting down.
+pub const DEFAULT_GRACE: u64 = 30;
+/// Number of requests to handle at once.
+pub const DEFAULT_WORKERS: usize = 10;
+/// Connections that can wait for a worker before we start turning
+/// them away.
+pub const DEFAULT_QUEUE: usize = 100;
+/// Seconds over which `rate-limit` requests are counted.
+pub const DEFAULT_RATE_WINDOW: u64 = 60;
+/// Selector for the stats page, if it's turned on.
+pub const DEFAULT_STATS_SELECTOR: &str = "/.phd/stats";
+/// Directory under the root holding custom error pages.
+pub const DEFAULT_ERROR_PAGES: &str = "/.errors";
+/// Most bytes of rendered menus to keep in memory.
+pub const DEFAULT_CACHE_SIZE: usize = 16 * 1024 * 1024;
+/// Widest text lines to write when converting documents to menus.
+pub const DEFAULT_WIDTH: usize = 70;
+/// Seconds between checks for changes when inotify isn't available.
+pub const DEFAULT_WATCH_INTERVAL: u64 = 5;
+
+/// Settings for a running server.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Root directory of the site.
+    pub root: String,
+    /// Hostname used when generating links.
+    pub host: String,
+    /// Port used when generating links.
+    pub port: u16,
+    /// Socket address to bind to, if set explicitly.
+    pub bind: Option<String>,
+    /// How long to wait for in-flight requests when shutting down.
+    pub grace: Duration,
+    /// User to switch to after binding.
+    pub user: Option<String>,
+    /// Group to switch to after binding.
+    pub group: Option<String>,
+    /// Whether to chroot into the site root after binding.
+    pub chroot: bool,
+    /// Most verbose status messages to show.
+    pub log_level: Level,
+    /// Write status messages to this file instead of stdout/stderr.
+    pub log_file: Option<String>,
+    /// Where to write the access log, or `-` for stdout.
+    pub access_log: Option<String>,
+    /// Layout of access log lines.
+    pub access_format: access::Format,
+    /// How connections are handled.
+    pub backend: Backend,
+    /// Number of worker threads.
+    pub workers: usize,
+    /// Most connections that can wait for a worker. 0 means no limit.
+    pub queue: usize,
+    /// What to do with new connections when the queue is full.
+    pub overflow: Overflow,
+    /// Most requests a client can make per `rate_window`. 0 means no
+    /// limit.
+    pub rate_limit: u32,
+    /// Window over which `rate_limit` is counted.
+    pub rate_window: Duration,
+    /// Most connections a client can have open at once. 0 means no
+    /// limit.
+    pub conn_limit: u32,
+    /// Which client networks can see which parts of the site.
+    pub acl: Acl,
+    /// Socket address to serve Prometheus metrics on, if any.
+    pub metrics: Option<String>,
+    /// Whether to serve server statistics at `stats_selector`.
+    pub stats: bool,
+    /// Selector for the stats page.
+    pub stats_selector: String,
+    /// Directory under the root with error pages like `404.gph`.
+    pub error_pages: String,
+    /// Whether to keep rendered menus in memory.
+    pub cache: bool,
+    /// Most bytes the cache can hold.
+    pub cache_size: usize,
+    /// Executable gophermaps whose output can be cached, as file name
+    /// patterns like `ignore`.
+    pub cache_scripts: Vec<String>,
+    /// Whether to watch the site for changes while caching.
+    pub watch: bool,
+    /// How often to check for changes when inotify isn't available.
+    pub watch_interval: Duration,
+    /// Whether to serve Markdown files as menus.
+    pub markdown: bool,
+    /// Whether to serve gemtext files as menus.
+    pub gemtext: bool,
+    /// Widest lines of text in menus made from documents.
+    pub width: usize,
+    /// Whether to wrap long info lines in gophermaps to `width`.
+    pub wrap: bool,
+    /// Whether to serve Atom feeds for `.reverse` directories.
+    pub feeds: bool,
+    /// Whether to fill in `{{templates}}` in gophermaps.
+    pub templates: bool,
+    /// Whether directories without a header or footer use their
+    /// parent's.
+    pub inherit: bool,
+    /// Whether to show headers and footers around gophermaps too.
+    pub map_headers: bool,
+    /// Extra file name patterns to hide from directory listings.
+    /// `*` matches any run of characters.
+    pub ignore: Vec<String>,
+    /// Gopher types to use for file extensions, instead of guessing.
+    pub types: HashMap<String, gopher::Type>,
+    /// Config file these settings were read from, if any.
+    path: Option<String>,
+    /// Settings given on the command line, applied after the file.
+    args: Vec<(String, String)>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            root: ".".into(),
+            host: DEFAULT_HOST.into(),
+            port: DEFAULT_PORT,
+            bind: None,
+            grace: Duration::from_secs(DEFAULT_GRACE),
+            user: None,
+            group: None,
+            chroot: false,
+            log_level: Level::Info,
+            log_file: None,
+            access_log: None,
+            access_format: access::Format::Common,
+            backend: Backend::Threads,
+            workers: DEFAULT_WORKERS,
+            queue: DEFAULT_QUEUE,
+            overflow: Overflow::Reject,
+            rate_limit: 0,
+            rate_window: Duration::from_secs(DEFAULT_RATE_WINDOW),
+            conn_limit: 0,
+            acl: Acl::default(),
+            metrics: None,
+            stats: false,
+            stats_selector: DEFAULT_STATS_SELECTOR.into(),
+            error_pages: DEFAULT_ERROR_PAGES.into(),
+            cache: false,
+            cache_size: DEFAULT_CACHE_SIZE,
+            cache_scripts: vec![],
+            watch: true,
+            watch_interval: Duration::from_secs(DEFAULT_WATCH_INTERVAL),
+            markdown: false,
+            gemtext: false,
+            width: DEFAULT_WIDTH,
+            wrap: false,
+            feeds: false,
+            templates: false,
+            inherit: false,
+            map_headers: false,
+            ignore: vec![],
+            types: HashMap::new(),
+            path: None,
+            args: vec![],
+        }
+    }
+}
+
+impl Config {
+    /// Build a config from an optional config file and a list of
+    /// `(key, value)` settings given on the command line.
+    pub fn new(path: Option<&str>, args: Vec<(String, String)>) -> Result<Config> {
+        let mut config = Config::default();
+        if let Some(path) = path {
+            let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+            config.parse(&text).map_err(|e| format!("{}:{}", path, e))?;
+            config.path = Some(path.into());
+        }
+        for (key, val) in &args {
+            config.set(key, val)?;
+        }
+        config.args = args;
+        Ok(config)
+    }
+
+    /// Re-read the config file, re-applying command line settings.
+    pub fn reload(&self) -> Result<Config> {
+        Config::new(self.path.as_deref(), self.args.clone())
+    }
+
+    /// Config file in use, if any.
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
+
+    /// Apply the `key = value` lines in a config file. Errors are
+    /// prefixed with the line number.
+    pub fn parse(&mut self, text: &str) -> Result<()> {
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap_or("").trim();
+            let val = match parts.next() {
+                Some(val) => val.trim(),
+                None => return Err(format!("{}: expected `key = value`", i + 1).into()),
+            };
+            self.set(key, val)
+                .map_err(|e| format!("{}: {}", i + 1, e))?;
+        }
+        Ok(())
+    }
+
+    /// Change a single setting.
+    pub fn set(&mut self, key: &str, val: &str) -> Result<()> {
+        match key {
+            "root" => self.root = val.into(),
+            "host" => self.host = val.into(),
+            "port" => self.port = val.parse().map_err(|_| format!("bad port: {}", val))?,
+            "bind" => {
+                val.parse::<SocketAddr>()
+                    .map_err(|_| format!("bad bind address: {}", val))?;
+                self.bind = Some(val.into());
+            }
+            "grace" => {
+                let secs = val
+                    .parse()
+                    .map_err(|_| format!("bad grace period: {}", val))?;
+                self.grace = Duration::from_secs(secs);
+            }
+            "user" => self.user = Some(val.into()),
+            "group" => self.group = Some(val.into()),
+            "chroot" => self.chroot = parse_bool(key, val)?,
+            "log-level" => {
+                self.log_level =
+                    Level::from(val).ok_or_else(|| format!("bad log level: {}", val))?
+            }
+            "log" => self.log_file = Some(val.into()),
+            "access-log" => self.access_log = Some(val.into()),
+            "access-format" => {
+                self.access_format = access::Format::from(val)
+                    .ok_or_else(|| format!("bad access log format: {}", val))?
+            }
+            "backend" => {
+                let backend = Backend::from(val)
+                    .ok_or_else(|| format!("backend should be threads or async, not: {}", val))?;
+                if backend == Backend::Async && !cfg!(feature = "async") {
+                    return Err("phd was built without async support. \
+                                Rebuild it with `--features async`."
+                        .into());
+                }
+                self.backend = backend;
+            }
+            "workers" => match val.parse() {
+                Ok(n) if n > 0 => self.workers = n,
+                _ => return Err(format!("bad worker count: {}", val).into()),
+            },
+            "queue" => {
+                self.queue = val
+                    .parse()
+                    .map_err(|_| format!("bad queue size: {}", val))?
+            }
+            "overflow" => {
+                self.overflow = Overflow::from(val)
+                    .ok_or_else(|| format!("overflow should be reject or block, not: {}", val))?
+            }
+            "rate-limit" => {
+                self.rate_limit = val
+                    .parse()
+                    .map_err(|_| format!("bad rate limit: {}", val))?
+            }
+            "rate-window" => {
+                let secs: u64 = val
+                    .parse()
+                    .map_err(|_| format!("bad rate window: {}", val))?;
+                if secs == 0 {
+                    return Err("rate-window must be at least 1 second".into());
+                }
+                self.rate_window = Duration::from_secs(secs);
+            }
+            "conn-limit" => {
+                self.conn_limit = val
+                    .parse()
+                    .map_err(|_| format!("bad connection limit: {}", val))?
+            }
+            "allow" => self.acl.push(Rule::parse(true, val)?),
+            "deny" => self.acl.push(Rule::parse(false, val)?),
+            "allow-scripts" => self.acl.push(Rule::parse_scripts(true, val)?),
+            "deny-scripts" => self.acl.push(Rule::parse_scripts(false, val)?),
+            "metrics" => {
+                val.parse::<SocketAddr>()
+                    .map_err(|_| format!("bad metrics address: {}", val))?;
+                self.metrics = Some(val.into());
+            }
+            "stats" => self.stats = parse_bool(key, val)?,
+            "stats-selector" => self.stats_selector = val.into(),
+            "error-pages" => self.error_pages = val.into(),
+            "cache" => self.cache = parse_bool(key, val)?,
+            "cache-size" => {
+                self.cache_size =
+                    parse_size(val).ok_or_else(|| format!("bad cache size: {}", val))?
+            }
+            "cache-scripts" => self.cache_scripts.push(val.into()),
+            "watch" => self.watch = parse_bool(key, val)?,
+            "watch-interval" => {
+                let secs: u64 = val
+                    .parse()
+                    .map_err(|_| format!("bad watch interval: {}", val))?;
+                if secs == 0 {
+                    return Err("watch-interval must be at least 1 second".into());
+                }
+                self.watch_interval = Duration::from_secs(secs);
+            }
+            "markdown" => self.markdown = parse_bool(key, val)?,
+            "gemtext" => self.gemtext = parse_bool(key, val)?,
+            "wrap" => self.wrap = parse_bool(key, val)?,
+            "feeds" => self.feeds = parse_bool(key, val)?,
+            "templates" => self.templates = parse_bool(key, val)?,
+            "inherit" => self.inherit = parse_bool(key, val)?,
+            "map-headers" => self.map_headers = parse_bool(key, val)?,
+            "width" => match val.parse() {
+                Ok(n) if n > 0 => self.width = n,
+                _ => return Err(format!("bad width: {}", val).into()),
+            },
+            "ignore" => self.ignore.push(val.into()),
+            _ if key.starts_with("type.") => {
+                let ext = key.trim_start_matches("type.");
+                let mut chars = val.chars();
+                let typ = match (chars.next(), chars.next()) {
+                    (Some(c), None) => gopher::Type::from(c),
+                    _ => None,
+                };
+                match typ {
+                    Some(typ) => self.types.insert(ext.to_lowercase(), typ),
+                    None => return Err(format!("bad gopher type: {}", val).into()),
+                };
+            }
+            _ => return Err(format!("unknown setting: {}", key).into()),
+        }
+        Ok(())
+    }
+
+    /// Socket address to listen on. If a port was given but no bind
+    /// address, we'll bind to that port on all interfaces.
+    pub fn bind_addr(&self) -> SocketAddr {
+        match &self.bind {
+            Some(addr) => addr.parse().unwrap(),
+            None if self.port != DEFAULT_PORT => format!("[::]:{}", self.port).parse().unwrap(),
+            None => DEFAULT_BIND.parse().unwrap(),
+        }
+    }
+
+    /// Should this file be hidden from directory listings?
+    pub fn is_ignored(&self, file_name: &str) -> bool {
+        self.ignore.iter().any(|pat| glob_match(pat, file_name))
+    }
+
+    /// Can the output of this executable gophermap be cached?
+    pub fn is_cacheable_script(&self, file_name: &str) -> bool {
+        self.cache_scripts
+            .iter()
+            .any(|pat| glob_match(pat, file_name))
+    }
+
+    /// The gopher type configured for this file's extension, if any.
+    pub fn type_for(&self, file_name: &str) -> Option<gopher::Type> {
+        let ext = file_name.rsplit('.').next()?;
+        if ext.len() == file_name.len() {
+            return None;
+        }
+        self.types.get(&ext.to_lowercase()).copied()
+    }
+}
+
+/// Parse a yes/no setting.
+fn parse_bool(key: &str, val: &str) -> Result<bool> {
+    match val {
+        "true" | "yes" | "on" | "1" => Ok(true),
+        "false" | "no" | "off" | "0" => Ok(false),
+        _ => Err(format!("{} should be true or false, not: {}", key, val).into()),
+    }
+}
+
+/// Parse a size in bytes, with an optional `K`, `M`, or `G` suffix.
+fn parse_size(val: &str) -> Option<usize> {
+    let (num, unit) = match val.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => val.split_at(i),
+        None => (val, ""),
+    };
+    let unit = match unit.to_uppercase().as_ref() {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        _ => return None,
+    };
+    num.parse::<usize>().ok()?.checked_mul(unit)
+}
+
+/// Match a file name against a pattern where `*` matches any run of
+/// characters.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    match pattern.find('*') {
+        None => pattern == name,
+        Some(i) => {
+            let (prefix, rest) = (&pattern[..i], &pattern[i + 1..]);
+            if !name.starts_with(prefix) {
+                return false;
+            }
+            let name = &name[prefix.len()..];
+            (0..=name.len())
+                .filter(|&j| name.is_char_boundary(j))
+                .any(|j| glob_match(rest, &name[j..]))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn test_parse_config() {
+        let mut config = Config::default();
+        config
+            .parse(
+                "# comment
+root = /srv/gopher
+host=gopher.example.com
+port = 70
+user = nobody
+chroot = yes
+
+ignore = *.bak
+ignore = secret
+type.MD = 0
+type.png = p
+",
+            )
+            .unwrap();
+        assert_eq!(config.root, "/srv/gopher");
+        assert_eq!(config.host, "gopher.example.com");
+        assert_eq!(config.port, 70);
+        assert_eq!(config.bind_addr(), "[::]:70".parse().unwrap());
+        assert_eq!(config.user.as_deref(), Some("nobody"));
+        assert!(config.chroot);
+        assert!(config.is_ignored("notes.bak"));
+        assert!(config.is_ignored("secret"));
+        assert!(!config.is_ignored("secrets"));
+        assert_eq!(config.type_for("README.md"), Some(gopher::Type::Text));
+        assert_eq!(config.type_for("cat.PNG"), Some(gopher::Type::PNG));
+        assert_eq!(config.type_for("png"), None);
+    }
+
+    #[test]
+    fn test_parse_config_errors() {
+        let mut config = Config::default();
+        assert_eq!(
+            config
+                .parse("host = x\nport = seventy")
+                .unwrap_err()
+                .to_string(),
+            "2: bad port: seventy"
+        );
+        assert_eq!(
+            config.parse("colour = blue").unwrap_err().to_string(),
+            "1: unknown setting: colour"
+        );
+        assert!(config.parse("type.md = zz").is_err());
+        assert!(config.parse("chroot = maybe").is_err());
+        assert!(config.parse("workers = 0").is_err());
+        assert!(config.parse("overflow = drop").is_err());
+        assert!(config.parse("cache-size = lots").is_err());
+        assert!(config.parse("width = 0").is_err());
+        assert!(config.parse("just some words").is_err());
+    }
+
+    #[test]
+    fn test_args_override_file() {
+        let dir = TempDir::new("config");
+        let path = dir.write("phd.conf", "host = from-file\nport = 70\n");
+        let path = path.to_string_lossy().to_string();
+        let args = vec![("host".to_string(), "from-args".to_string())];
+        let config = Config::new(Some(&path), args).unwrap();
+        assert_eq!(config.host, "from-args");
+        assert_eq!(config.port, 70);
+
+        fs::write(&path, "port = 7777\n").unwrap();
+        let config = config.reload().unwrap();
+        assert_eq!(config.host, "from-args");
+        assert_eq!(config.port, 7777);
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1000"), Some(1000));
+        assert_eq!(parse_size("64k"), Some(64 * 1024));
+        assert_eq!(parse_size("16MB"), Some(16 * 1024 * 1024));
+        assert_eq!(parse_size("1G"), Some(1 << 30));
+        assert_eq!(parse_size("M"), None);
+        assert_eq!(parse_size("5 bananas"), None);
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("*.bak", ".bak"));
+        assert!(glob_match("draft-*.txt", "draft-one.txt"));
+        assert!(!glob_match("draft-*.txt", "draft-one.md"));
+        assert!(glob_match("a*b*c", "aXXbYYc"));
+    }
+}
@@ -0,0 +1,156 @@
+//! Just enough calendar math to print timestamps, without pulling in
+//! a date library. Everything is UTC.
+
+use std::{
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// A point in time, broken down into its UTC calendar parts.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Date {
+    /// Year, e.g. 2020.
+    pub year: i64,
+    /// Month, 1-12.
+    pub month: u32,
+    /// Day of the month, 1-31.
+    pub day: u32,
+    /// Hour, 0-23.
+    pub hour: u32,
+    /// Minute, 0-59.
+    pub minute: u32,
+    /// Second, 0-59.
+    pub second: u32,
+}
+
+impl Date {
+    /// The current time.
+    pub fn now() -> Date {
+        Date::from(SystemTime::now())
+    }
+
+    /// Convert a SystemTime. Times before 1970 are clamped to 1970.
+    pub fn from(time: SystemTime) -> Date {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Date::from_unix(secs as i64)
+    }
+
+    /// Convert seconds since the Unix epoch.
+    pub fn from_unix(secs: i64) -> Date {
+        let days = secs.div_euclid(86_400);
+        let rem = secs.rem_euclid(86_400);
+
+        // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z - era * 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        Date {
+            year,
+            month: month as u32,
+            day: day as u32,
+            hour: (rem / 3600) as u32,
+            minute: (rem % 3600 / 60) as u32,
+            second: (rem % 60) as u32,
+        }
+    }
+
+    /// Build a date from a `YYYY-MM-DD` prefix, e.g. the start of a
+    /// file name like `2020-01-31-hello.txt`.
+    pub fn parse_prefix(s: &str) -> Option<Date> {
+        let b = s.as_bytes();
+        if b.len() < 10 || b[4] != b'-' || b[7] != b'-' {
+            return None;
+        }
+        let num = |range: std::ops::Range<usize>| -> Option<u32> {
+            let part = s.get(range)?;
+            if part.bytes().all(|c| c.is_ascii_digit()) {
+                part.parse().ok()
+            } else {
+                None
+            }
+        };
+        let (year, month, day) = (num(0..4)?, num(5..7)?, num(8..10)?);
+        if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+            return None;
+        }
+        Some(Date {
+            year: year as i64,
+            month,
+            day,
+            hour: 0,
+            minute: 0,
+            second: 0,
+        })
+    }
+
+    /// Common Log Format timestamp, e.g. `10/Oct/2000:13:55:36 +0000`.
+    pub fn clf(&self) -> String {
+        format!(
+            "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+            self.day,
+            MONTHS[self.month as usize - 1],
+            self.year,
+            self.hour,
+            self.minute,
+            self.second
+        )
+    }
+
+    /// RFC 3339 timestamp, e.g. `2000-10-10T13:55:36Z`.
+    pub fn rfc3339(&self) -> String {
+        format!(
+            "{}T{:02}:{:02}:{:02}Z",
+            self, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Displays as `YYYY-MM-DD`.
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_unix() {
+        assert_eq!(Date::from_unix(0).rfc3339(), "1970-01-01T00:00:00Z");
+        assert_eq!(
+            Date::from_unix(971_186_136).clf(),
+            "10/Oct/2000:13:55:36 +0000"
+        );
+        assert_eq!(Date::from_unix(951_782_400).to_string(), "2000-02-29");
+        assert_eq!(
+            Date::from_unix(1_767_225_599).rfc3339(),
+            "2025-12-31T23:59:59Z"
+        );
+    }
+
+    #[test]
+    fn test_parse_prefix() {
+        let date = Date::parse_prefix("2020-01-31-hello.txt").unwrap();
+        assert_eq!(date.to_string(), "2020-01-31");
+        assert_eq!(date.rfc3339(), "2020-01-31T00:00:00Z");
+        assert_eq!(Date::parse_prefix("2020-13-01"), None);
+        assert_eq!(Date::parse_prefix("hello.txt"), None);
+        assert_eq!(Date::parse_prefix("2020-1-31"), None);
+    }
+}
@@ -0,0 +1,481 @@
+//! An event-driven backend, built with `--features async` and used
+//! when `backend = async`.
+//!
+//! One thread reads requests and writes responses for every open
+//! connection, so clients on slow links don't each hold on to a
+//! worker. Workers only build responses, using the same code as the
+//! threaded backend, and files are streamed out by the event loop a
+//! chunk at a time instead of being copied by a worker.
+
+use crate::{
+    access::AccessLog,
+    color,
+    limit::Permit,
+    log::Logger,
+    server::{self, Output, Served},
+    Request, Result,
+};
+use mio::{net::TcpStream, Events, Interest, Poll, Token, Waker};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io::{self, Read, Write},
+    mem,
+    net::{self, SocketAddr},
+    os::unix::io::AsRawFd,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use threadpool::ThreadPool;
+
+/// Token for waking the event loop when there's a message for it.
+const WAKER: Token = Token(0);
+
+/// How long to wait between checks for idle connections.
+const TICK: Duration = Duration::from_secs(1);
+
+/// Drop clients that haven't sent or received anything for this long.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Stop reading a request line after this many bytes.
+const MAX_REQUEST_SIZE: usize = 16 * 1024;
+
+/// How much of a file to read at a time when streaming it.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// How often to check whether connections have closed during a
+/// graceful shutdown.
+const DRAIN_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Handle to the event loop thread.
+#[derive(Debug)]
+pub struct EventLoop {
+    tx: Sender<Msg>,
+    waker: Arc<Waker>,
+    open: Arc<AtomicUsize>,
+}
+
+/// Messages for the event loop thread.
+enum Msg {
+    /// A newly accepted client.
+    New(Box<Conn>),
+    /// A worker has finished building the response for a client.
+    Done(Token, Pending, Option<Served>),
+}
+
+impl EventLoop {
+    /// Start the event loop thread. Responses are built on `pool`.
+    pub fn spawn(pool: ThreadPool, log: Logger) -> Result<EventLoop> {
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        let (tx, rx) = mpsc::channel();
+        let open = Arc::new(AtomicUsize::new(0));
+        let mut looper = Looper {
+            poll,
+            rx,
+            tx: tx.clone(),
+            waker: Arc::clone(&waker),
+            pool,
+            log,
+            open: Arc::clone(&open),
+            conns: HashMap::new(),
+            next: 1,
+        };
+        thread::spawn(move || looper.run());
+        Ok(EventLoop { tx, waker, open })
+    }
+
+    /// Hand a newly accepted client to the event loop. The permit, if
+    /// any, is held until the connection closes.
+    pub fn add(
+        &self,
+        stream: net::TcpStream,
+        req: Request,
+        access: Option<Arc<AccessLog>>,
+        permit: Option<Permit>,
+    ) -> Result<()> {
+        stream.set_nonblocking(true)?;
+        let peer = stream.peer_addr().ok();
+        let conn = Conn {
+            stream: TcpStream::from_std(stream),
+            peer,
+            req: Some(req),
+            access,
+            _permit: permit,
+            state: State::Reading(vec![]),
+            deadline: Instant::now() + IDLE_TIMEOUT,
+            served: None,
+            sent: 0,
+        };
+        self.open.fetch_add(1, Ordering::Relaxed);
+        self.tx
+            .send(Msg::New(Box::new(conn)))
+            .map_err(|_| "event loop stopped")?;
+        self.waker.wake()?;
+        Ok(())
+    }
+
+    /// Number of open connections.
+    pub fn open(&self) -> usize {
+        self.open.load(Ordering::Relaxed)
+    }
+
+    /// Wait until every connection has closed or `deadline` passes.
+    /// Returns the number still open.
+    pub fn drain(&self, deadline: Instant) -> usize {
+        while self.open() > 0 && Instant::now() < deadline {
+            thread::sleep(DRAIN_INTERVAL);
+        }
+        self.open()
+    }
+}
+
+/// A response waiting to be sent.
+#[derive(Default)]
+struct Pending {
+    parts: VecDeque<Part>,
+}
+
+enum Part {
+    Bytes(Vec<u8>),
+    File(File),
+}
+
+impl Write for Pending {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.parts.back_mut() {
+            Some(Part::Bytes(bytes)) => bytes.extend_from_slice(buf),
+            _ => self.parts.push_back(Part::Bytes(buf.to_vec())),
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Output for Pending {
+    /// Files are streamed by the event loop later, so just remember it.
+    fn send_file(&mut self, file: File) -> io::Result<u64> {
+        let len = file.metadata()?.len();
+        self.parts.push_back(Part::File(file));
+        Ok(len)
+    }
+}
+
+/// Where a connection is at.
+enum State {
+    /// Waiting for the request line.
+    Reading(Vec<u8>),
+    /// A worker is building the response.
+    Working,
+    /// Sending the response. `buf[pos..]` is still to be written.
+    Writing {
+        parts: VecDeque<Part>,
+        buf: Vec<u8>,
+        pos: usize,
+    },
+}
+
+/// A single client.
+struct Conn {
+    stream: TcpStream,
+    peer: Option<SocketAddr>,
+    req: Option<Request>,
+    access: Option<Arc<AccessLog>>,
+    _permit: Option<Permit>,
+    state: State,
+    deadline: Instant,
+    /// The response being sent, for the access log.
+    served: Option<Served>,
+    /// Bytes the client has been sent so far.
+    sent: u64,
+}
+
+/// The event loop itself.
+struct Looper {
+    poll: Poll,
+    rx: Receiver<Msg>,
+    tx: Sender<Msg>,
+    waker: Arc<Waker>,
+    pool: ThreadPool,
+    log: Logger,
+    open: Arc<AtomicUsize>,
+    conns: HashMap<Token, Conn>,
+    next: usize,
+}
+
+impl Looper {
+    fn run(&mut self) {
+        let mut events = Events::with_capacity(1024);
+        loop {
+            if let Err(e) = self.poll.poll(&mut events, Some(TICK)) {
+                if e.kind() != io::ErrorKind::Interrupted {
+                    error!(
+                        self.log,
+                        "{}» Event loop: {}{}",
+                        color::Red,
+                        e,
+                        color::Reset
+                    );
+                    thread::sleep(TICK);
+                }
+                continue;
+            }
+            for event in events.iter() {
+                if event.token() != WAKER {
+                    self.ready(event.token());
+                }
+            }
+            while let Ok(msg) = self.rx.try_recv() {
+                match msg {
+                    Msg::New(conn) => self.insert(*conn),
+                    Msg::Done(token, pending, served) => self.done(token, pending, served),
+                }
+            }
+            self.expire();
+        }
+    }
+
+    /// Start watching a new client.
+    fn insert(&mut self, mut conn: Conn) {
+        let token = Token(self.next);
+        self.next += 1;
+        match self
+            .poll
+            .registry()
+            .register(&mut conn.stream, token, Interest::READABLE)
+        {
+            Ok(()) => {
+                self.conns.insert(token, conn);
+                // The request may have arrived before we registered.
+                self.ready(token);
+            }
+            Err(e) => {
+                error!(self.log, "{}└ {}{}", color::Red, e, color::Reset);
+                self.open.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// A client's socket can be read or written.
+    fn ready(&mut self, token: Token) {
+        let result = match self.conns.get_mut(&token) {
+            Some(conn) => match conn.state {
+                State::Reading(_) => read_request(conn),
+                State::Working => Ok(false),
+                State::Writing { .. } => write_response(conn),
+            },
+            None => return,
+        };
+        match result {
+            Ok(false) => {}
+            Ok(true) => {
+                if let Some(State::Reading(_)) = self.conns.get(&token).map(|c| &c.state) {
+                    self.work(token);
+                } else {
+                    self.close(token);
+                }
+            }
+            Err(e) => {
+                debug!(self.log, "{}└ {}{}", color::Red, e, color::Reset);
+                self.close(token);
+            }
+        }
+    }
+
+    /// The request line is in: build the response on a worker.
+    fn work(&mut self, token: Token) {
+        let conn = match self.conns.get_mut(&token) {
+            Some(conn) => conn,
+            None => return,
+        };
+        let line = match mem::replace(&mut conn.state, State::Working) {
+            State::Reading(line) => line,
+            _ => return,
+        };
+        let req = match conn.req.take() {
+            Some(req) => req,
+            None => return,
+        };
+        let peer = conn.peer;
+        let (tx, waker) = (self.tx.clone(), Arc::clone(&self.waker));
+        self.pool.execute(move || {
+            let log = req.log.clone();
+            let mut out = Pending::default();
+            let (served, result) = server::build_response(&line[..], &mut out, req, peer);
+            if let Err(e) = result {
+                error!(log, "{}└ {}{}", color::Red, e, color::Reset);
+            }
+            if tx.send(Msg::Done(token, out, served)).is_ok() {
+                waker.wake();
+            }
+        });
+    }
+
+    /// A worker has built a response: start sending it.
+    fn done(&mut self, token: Token, pending: Pending, served: Option<Served>) {
+        let conn = match self.conns.get_mut(&token) {
+            Some(conn) => conn,
+            None => return,
+        };
+        conn.served = served;
+        conn.state = State::Writing {
+            parts: pending.parts,
+            buf: vec![],
+            pos: 0,
+        };
+        conn.deadline = Instant::now() + IDLE_TIMEOUT;
+        if let Err(e) = self
+            .poll
+            .registry()
+            .reregister(&mut conn.stream, token, Interest::WRITABLE)
+        {
+            debug!(self.log, "{}└ {}{}", color::Red, e, color::Reset);
+            self.close(token);
+            return;
+        }
+        self.ready(token);
+    }
+
+    /// Close connections that have been quiet too long.
+    fn expire(&mut self) {
+        let now = Instant::now();
+        let idle: Vec<_> = self
+            .conns
+            .iter()
+            .filter(|(_, c)| !matches!(c.state, State::Working) && c.deadline < now)
+            .map(|(token, _)| *token)
+            .collect();
+        for token in idle {
+            debug!(
+                self.log,
+                "{}└ Timed out{} waiting for {:?}",
+                color::Red,
+                color::Reset,
+                self.conns.get(&token).and_then(|c| c.peer)
+            );
+            self.close(token);
+        }
+    }
+
+    /// Drop a client, logging how much of its response got through.
+    fn close(&mut self, token: Token) {
+        if let Some(conn) = self.conns.remove(&token) {
+            if let (Some(served), Some(access)) = (&conn.served, &conn.access) {
+                served.log(access, conn.sent);
+            }
+            self.open.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Read as much of the request line as is available. Returns true
+/// once we have all of it.
+fn read_request(conn: &mut Conn) -> io::Result<bool> {
+    let line = match &mut conn.state {
+        State::Reading(line) => line,
+        _ => return Ok(false),
+    };
+    let mut buf = [0; 4096];
+    loop {
+        match conn.stream.read(&mut buf) {
+            Ok(0) => return Ok(true),
+            Ok(n) => {
+                line.extend_from_slice(&buf[..n]);
+                conn.deadline = Instant::now() + IDLE_TIMEOUT;
+                if line.contains(&b'\n') || line.len() >= MAX_REQUEST_SIZE {
+                    return Ok(true);
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Write as much of the response as the client will take. Returns true
+/// once it's all been sent.
+fn write_response(conn: &mut Conn) -> io::Result<bool> {
+    let (parts, buf, pos) = match &mut conn.state {
+        State::Writing { parts, buf, pos } => (parts, buf, pos),
+        _ => return Ok(false),
+    };
+    loop {
+        if *pos < buf.len() {
+            match conn.stream.write(&buf[*pos..]) {
+                Ok(n) => {
+                    *pos += n;
+                    conn.sent += n as u64;
+                    conn.deadline = Instant::now() + IDLE_TIMEOUT;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+            continue;
+        }
+        *pos = 0;
+        match parts.front_mut() {
+            None => return Ok(true),
+            Some(Part::Bytes(bytes)) => {
+                *buf = mem::take(bytes);
+                parts.pop_front();
+            }
+            Some(Part::File(file)) => {
+                #[cfg(target_os = "linux")]
+                match server::sendfile(conn.stream.as_raw_fd(), file, CHUNK_SIZE) {
+                    Ok(0) => {
+                        parts.pop_front();
+                        continue;
+                    }
+                    Ok(n) => {
+                        conn.sent += n as u64;
+                        conn.deadline = Instant::now() + IDLE_TIMEOUT;
+                        continue;
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(ref e) if server::sendfile_unsupported(e) => {}
+                    Err(e) => return Err(e),
+                }
+                buf.resize(CHUNK_SIZE, 0);
+                let n = file.read(buf)?;
+                buf.truncate(n);
+                if n == 0 {
+                    parts.pop_front();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pending() {
+        let mut out = Pending::default();
+        write!(out, "i hi\r\n");
+        write!(out, ".\r\n");
+        let file = File::open("Cargo.toml").unwrap();
+        let len = file.metadata().unwrap().len();
+        assert_eq!(out.send_file(file).unwrap(), len);
+        write!(out, "after");
+
+        assert_eq!(out.parts.len(), 3);
+        match &out.parts[0] {
+            Part::Bytes(bytes) => assert_eq!(bytes, b"i hi\r\n.\r\n"),
+            Part::File(_) => panic!("expected bytes"),
+        }
+        assert!(matches!(out.parts[1], Part::File(_)));
+    }
+}
@@ -0,0 +1,192 @@
+//! Atom feeds for phlogs. A directory with a `.reverse` file is taken
+//! to be a list of posts, newest first, and gets a feed at
+//! `DIR/feed.xml` so gopher-aware feed readers can subscribe to it.
+//!
+//! Each post's title is the first line of the file, or its name if
+//! that doesn't work out. Its date comes from a `YYYY-MM-DD` prefix on
+//! the file name, like `2020-01-31-hello.txt`, or else from when the
+//! file was last modified.
+
+use crate::{date::Date, gopher::Type};
+use std::{
+    fs,
+    io::{BufRead, BufReader},
+    path::Path,
+};
+
+/// Name of the feed in a phlog directory.
+pub const FEED_NAME: &str = "feed.xml";
+
+/// Most posts to put in a feed.
+const MAX_ENTRIES: usize = 50;
+
+/// Longest title we'll take from the first line of a post.
+const MAX_TITLE: usize = 100;
+
+/// A post in a phlog.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    /// What the post is called.
+    pub title: String,
+    /// Gopher type of the post.
+    pub typ: Type,
+    /// Selector for the post.
+    pub selector: String,
+    /// When the post was written.
+    pub date: Date,
+}
+
+impl Entry {
+    /// Describe the post at `path`, served with `typ` at `selector`.
+    pub fn new(path: &Path, typ: Type, selector: &str) -> Entry {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let date = Date::parse_prefix(&name).unwrap_or_else(|| {
+            fs::metadata(path)
+                .and_then(|meta| meta.modified())
+                .map(Date::from)
+                .unwrap_or_else(|_| Date::from_unix(0))
+        });
+        let title = if typ == Type::Text || typ == Type::Menu {
+            first_line(path)
+        } else {
+            None
+        };
+        Entry {
+            title: title.unwrap_or(name),
+            typ,
+            selector: selector.into(),
+            date,
+        }
+    }
+}
+
+/// The first line of text in a file, as a title.
+fn first_line(path: &Path) -> Option<String> {
+    let file = fs::File::open(path).ok()?;
+    for line in BufReader::new(file).lines().take(10) {
+        let line = line.ok()?;
+        // Skip blank lines, shebangs, and links, and drop Markdown and
+        // gemtext heading marks.
+        let line = line.trim();
+        let link = line.starts_with('[') && line.contains('|');
+        if line.is_empty() || link || line.starts_with("#!") || line.contains('\t') {
+            continue;
+        }
+        let line = line.trim_start_matches('#').trim();
+        if line.is_empty() {
+            continue;
+        }
+        return Some(line.chars().take(MAX_TITLE).collect());
+    }
+    None
+}
+
+/// Build an Atom feed called `title` for the directory at `selector`,
+/// with links to `host` and `port`. `entries` should be newest first.
+pub fn atom(title: &str, selector: &str, entries: &[Entry], host: &str, port: u16) -> String {
+    let base = format!("gopher://{}:{}", host, port);
+    let dir = selector.trim_end_matches('/');
+    let updated = entries
+        .iter()
+        .take(MAX_ENTRIES)
+        .map(|entry| entry.date)
+        .max()
+        .unwrap_or_else(|| Date::from_unix(0));
+
+    let mut out = String::new();
+    out.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    out.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    out.push_str(&format!("  <title>{}</title>\n", escape(title)));
+    out.push_str(&format!("  <id>{}/1{}</id>\n", base, escape(dir)));
+    out.push_str(&format!("  <link href=\"{}/1{}\"/>\n", base, escape(dir)));
+    out.push_str(&format!(
+        "  <link rel=\"self\" href=\"{}/0{}/{}\"/>\n",
+        base,
+        escape(dir),
+        FEED_NAME
+    ));
+    out.push_str(&format!("  <updated>{}</updated>\n", updated.rfc3339()));
+    out.push_str(&format!(
+        "  <author><name>{}</name></author>\n",
+        escape(host)
+    ));
+    for entry in entries.iter().take(MAX_ENTRIES) {
+        let url = format!("{}/{}{}", base, entry.typ, escape(&entry.selector));
+        out.push_str("  <entry>\n");
+        out.push_str(&format!("    <title>{}</title>\n", escape(&entry.title)));
+        out.push_str(&format!("    <id>{}</id>\n", url));
+        out.push_str(&format!("    <link href=\"{}\"/>\n", url));
+        out.push_str(&format!(
+            "    <updated>{}</updated>\n",
+            entry.date.rfc3339()
+        ));
+        out.push_str("  </entry>\n");
+    }
+    out.push_str("</feed>\n");
+    out
+}
+
+/// Make text safe to put in XML.
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            c if c.is_control() && c != '\t' => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn test_entry() {
+        let dir = TempDir::new("feed-entry");
+        let post = dir.write(
+            "2020-01-31-hello.md",
+            "\n# Hello & welcome\n\nFirst post.\n",
+        );
+        let entry = Entry::new(&post, Type::Text, "/phlog/2020-01-31-hello.md");
+        assert_eq!(entry.title, "Hello & welcome");
+        assert_eq!(entry.date.to_string(), "2020-01-31");
+
+        let pic = dir.write("cat.png", "");
+        let entry = Entry::new(&pic, Type::PNG, "/phlog/cat.png");
+        assert_eq!(entry.title, "cat.png");
+        assert_eq!(
+            entry.date,
+            Date::from(fs::metadata(&pic).unwrap().modified().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_atom() {
+        let entries = vec![Entry {
+            title: "Fish & <chips>".into(),
+            typ: Type::Text,
+            selector: "/phlog/2020-01-31-fish.txt".into(),
+            date: Date::parse_prefix("2020-01-31").unwrap(),
+        }];
+        let xml = atom("phlog", "/phlog/", &entries, "example.com", 70);
+        assert!(xml.contains("<id>gopher://example.com:70/1/phlog</id>"));
+        assert!(xml.contains("href=\"gopher://example.com:70/0/phlog/feed.xml\""));
+        assert!(xml.contains("<updated>2020-01-31T00:00:00Z</updated>"));
+        assert!(xml.contains("<title>Fish &amp; &lt;chips&gt;</title>"));
+        assert!(
+            xml.contains("<link href=\"gopher://example.com:70/0/phlog/2020-01-31-fish.txt\"/>")
+        );
+        assert_eq!(escape("a\"b'\u{7}"), "a&quot;b&apos;");
+    }
+}
@@ -0,0 +1,130 @@
+//! Turn Gemini's gemtext into something a Gopher menu can show. It's a
+//! line-based format, so this is mostly a matter of wrapping long
+//! lines and turning `=>` lines into links:
+//!
+//! ```text
+//! # My capsule
+//! Welcome! Long lines are wrapped.
+//! => /phlog/ My phlog
+//! => gemini://example.com Elsewhere
+//! ```
+//!
+//! Preformatted blocks between ```` ``` ```` lines are kept as they are.
+
+use crate::markdown::{self, Line};
+use unicode_width::UnicodeWidthStr;
+
+/// Convert gemtext to lines at most `width` columns wide.
+/// Preformatted text isn't wrapped.
+pub fn convert(gemtext: &str, width: usize) -> Vec<Line> {
+    let mut out = vec![];
+    let mut pre = false;
+
+    for line in gemtext.lines() {
+        let line = line.trim_end_matches('\r');
+        if line.starts_with("```") {
+            pre = !pre;
+            continue;
+        }
+        if pre {
+            out.push(Line::Text(markdown::detab(line)));
+            continue;
+        }
+        if let Some(rest) = line.strip_prefix("=>") {
+            let rest = rest.trim();
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let (target, text) = (&rest[..end], rest[end..].trim());
+            if target.is_empty() {
+                continue;
+            }
+            out.push(Line::Link {
+                text: markdown::detab(if text.is_empty() { target } else { text }),
+                target: target.into(),
+            });
+            continue;
+        }
+        if let Some((level, title)) = heading(line) {
+            let title = markdown::detab(title);
+            let len = title.width();
+            out.push(Line::Text(title));
+            match level {
+                1 => out.push(Line::Text("=".repeat(len))),
+                2 => out.push(Line::Text("-".repeat(len))),
+                _ => {}
+            }
+            continue;
+        }
+        let (first, rest, text) = if let Some(text) = line.strip_prefix("* ") {
+            ("- ", "  ", text)
+        } else if let Some(text) = line.strip_prefix('>') {
+            ("> ", "> ", text)
+        } else {
+            ("", "", line)
+        };
+        if text.trim().is_empty() && first.is_empty() {
+            out.push(Line::Text(String::new()));
+            continue;
+        }
+        for line in markdown::wrap(text, width, first, rest) {
+            out.push(Line::Text(line));
+        }
+    }
+    out
+}
+
+/// `## Title` => (2, "Title"). Gemtext only has three levels.
+fn heading(line: &str) -> Option<(usize, &str)> {
+    let level = line.chars().take_while(|&c| c == '#').count();
+    if (1..=3).contains(&level) {
+        Some((level, line[level..].trim()))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(s: &str) -> Line {
+        Line::Text(s.into())
+    }
+
+    fn link(text: &str, target: &str) -> Line {
+        Line::Link {
+            text: text.into(),
+            target: target.into(),
+        }
+    }
+
+    #[test]
+    fn test_convert() {
+        let gmi = "# Capsule\r\nA line that is long enough to wrap.\n\n\
+                   =>/phlog/\tMy phlog\n=> gemini://example.com\n=>\n\
+                   * an item that wraps\n> quoted\n### Small\n\
+                   ```ascii art\n  /\\_/\\\n\t( o.o )\n```\n";
+        assert_eq!(
+            convert(gmi, 16),
+            vec![
+                text("Capsule"),
+                text("======="),
+                text("A line that is"),
+                text("long enough to"),
+                text("wrap."),
+                text(""),
+                link("My phlog", "/phlog/"),
+                link("gemini://example.com", "gemini://example.com"),
+                text("- an item that"),
+                text("  wraps"),
+                text("> quoted"),
+                text("Small"),
+                text("  /\\_/\\"),
+                text("    ( o.o )"),
+            ]
+        );
+        assert_eq!(
+            convert("## A\tB\n=> /c D\tE\n", 16),
+            vec![text("A    B"), text("------"), link("D    E", "/c")]
+        );
+    }
+}
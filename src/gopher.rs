@@ -54,18 +54,18 @@ impl Type {
 
     /// Is this something we can download?
     pub fn is_download(self) -> bool {
-        match self {
+        matches!(
+            self,
             Type::Binhex
-            | Type::DOSFile
-            | Type::UUEncoded
-            | Type::Binary
-            | Type::GIF
-            | Type::Image
-            | Type::PNG
-            | Type::Sound
-            | Type::Document => true,
-            _ => false,
-        }
+                | Type::DOSFile
+                | Type::UUEncoded
+                | Type::Binary
+                | Type::GIF
+                | Type::Image
+                | Type::PNG
+                | Type::Sound
+                | Type::Document
+        )
     }
 
     /// Gopher Item Type to RFC char.
@@ -10,12 +10,34 @@
 #![warn(missing_docs)]
 #![allow(clippy::while_let_on_iterator)]
 
+pub mod access;
+pub mod acl;
+pub mod cache;
 pub mod color;
+pub mod config;
+pub mod date;
+pub mod feed;
+pub mod gemtext;
 pub mod gopher;
+pub mod limit;
+#[macro_use]
+pub mod log;
+#[cfg(feature = "async")]
+pub mod event;
+pub mod markdown;
+pub mod metrics;
+pub mod preview;
+pub mod privileges;
 pub mod request;
 pub mod server;
+pub mod snapshot;
+pub mod stats;
+pub mod template;
+#[cfg(test)]
+mod testing;
+pub mod watch;
 
-pub use crate::request::Request;
+pub use crate::{config::Config, request::Request};
 
 /// Alias for a generic Result type.
 pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
@@ -0,0 +1,245 @@
+//! Per-client limits, so one busy crawler can't tie up every worker.
+//!
+//! Each client IP may make `rate-limit` connections per `rate-window`
+//! and have `conn-limit` connections open at once. Clients over either
+//! limit are told to slow down before any work is queued. IPv6 clients
+//! are counted by /64, since that's usually one household or machine.
+
+use crate::Config;
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, Ipv6Addr},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+/// Most clients we'll keep track of. Once there are this many, new
+/// clients are turned away until old ones' windows run out.
+const MAX_CLIENTS: usize = 65_536;
+
+/// Why a client was turned away.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Refusal {
+    /// Too many requests in the current window.
+    Rate,
+    /// Too many connections open at once.
+    Connections,
+    /// Too many other clients being tracked.
+    Clients,
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Refusal::Rate => write!(f, "too many requests"),
+            Refusal::Connections => write!(f, "too many connections"),
+            Refusal::Clients => write!(f, "too many clients"),
+        }
+    }
+}
+
+/// What we know about one client.
+#[derive(Debug)]
+struct Client {
+    /// When the current rate window started.
+    window_start: Instant,
+    /// Requests made in the current window.
+    requests: u32,
+    /// Connections open right now.
+    open: u32,
+}
+
+/// Tracks requests and open connections for each client IP.
+#[derive(Debug, Default)]
+pub struct Limiter {
+    clients: Mutex<Clients>,
+}
+
+#[derive(Debug, Default)]
+struct Clients {
+    by_ip: HashMap<IpAddr, Client>,
+    /// When clients that are done were last forgotten.
+    pruned: Option<Instant>,
+}
+
+/// Held while a client's connection is open. Dropping it frees up
+/// one of the client's connection slots.
+#[derive(Debug)]
+pub struct Permit {
+    limiter: Arc<Limiter>,
+    ip: IpAddr,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut clients = self.limiter.clients.lock().unwrap();
+        if let Some(client) = clients.by_ip.get_mut(&self.ip) {
+            client.open = client.open.saturating_sub(1);
+        }
+    }
+}
+
+impl Limiter {
+    /// Check whether a new connection from `ip` is allowed under the
+    /// limits in `config`. On success, the returned `Permit` should
+    /// be kept until the connection is closed.
+    pub fn admit(self: &Arc<Self>, ip: IpAddr, config: &Config) -> Result<Permit, Refusal> {
+        self.admit_at(ip, config, Instant::now())
+    }
+
+    fn admit_at(
+        self: &Arc<Self>,
+        ip: IpAddr,
+        config: &Config,
+        now: Instant,
+    ) -> Result<Permit, Refusal> {
+        let ip = group(ip);
+        let mut clients = self.clients.lock().unwrap();
+        // Forget clients that are done, but only once a window: it
+        // means looking at all of them.
+        if clients
+            .pruned
+            .is_none_or(|pruned| now.duration_since(pruned) >= config.rate_window)
+        {
+            clients.by_ip.retain(|_, c| {
+                c.open > 0 || now.duration_since(c.window_start) < config.rate_window
+            });
+            clients.pruned = Some(now);
+        }
+        if clients.by_ip.len() >= MAX_CLIENTS && !clients.by_ip.contains_key(&ip) {
+            return Err(Refusal::Clients);
+        }
+
+        let client = clients.by_ip.entry(ip).or_insert(Client {
+            window_start: now,
+            requests: 0,
+            open: 0,
+        });
+        if now.duration_since(client.window_start) >= config.rate_window {
+            client.window_start = now;
+            client.requests = 0;
+        }
+        if config.conn_limit > 0 && client.open >= config.conn_limit {
+            return Err(Refusal::Connections);
+        }
+        if config.rate_limit > 0 && client.requests >= config.rate_limit {
+            return Err(Refusal::Rate);
+        }
+        client.requests += 1;
+        client.open += 1;
+        Ok(Permit {
+            limiter: Arc::clone(self),
+            ip,
+        })
+    }
+
+    /// Number of clients being tracked.
+    pub fn len(&self) -> usize {
+        self.clients.lock().unwrap().by_ip.len()
+    }
+
+    /// Are no clients being tracked?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// The address `ip` is counted under: IPv4 addresses as they are,
+/// and IPv6 addresses by their /64.
+fn group(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(ip) => {
+            let mut segments = ip.segments();
+            segments[4..].fill(0);
+            IpAddr::V6(Ipv6Addr::from(segments))
+        }
+        ip => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limit() {
+        let mut config = Config::default();
+        config.rate_limit = 2;
+        let limiter = Arc::new(Limiter::default());
+        let ip = "10.0.0.1".parse().unwrap();
+        let start = Instant::now();
+
+        assert!(limiter.admit_at(ip, &config, start).is_ok());
+        assert!(limiter.admit_at(ip, &config, start).is_ok());
+        assert_eq!(
+            limiter.admit_at(ip, &config, start).unwrap_err(),
+            Refusal::Rate
+        );
+        assert!(limiter
+            .admit_at("10.0.0.2".parse().unwrap(), &config, start)
+            .is_ok());
+        let later = start + config.rate_window;
+        assert!(limiter.admit_at(ip, &config, later).is_ok());
+        assert_eq!(limiter.len(), 1);
+    }
+
+    #[test]
+    fn test_conn_limit() {
+        let mut config = Config::default();
+        config.conn_limit = 1;
+        let limiter = Arc::new(Limiter::default());
+        let ip: IpAddr = "::ffff:10.0.0.1".parse().unwrap();
+        let now = Instant::now();
+
+        let permit = limiter.admit_at(ip, &config, now).unwrap();
+        assert_eq!(
+            limiter
+                .admit_at("10.0.0.1".parse().unwrap(), &config, now)
+                .unwrap_err(),
+            Refusal::Connections
+        );
+        drop(permit);
+        assert!(limiter.admit_at(ip, &config, now).is_ok());
+
+        // Addresses in the same IPv6 /64 share their limits.
+        let _permit = limiter
+            .admit_at("2001:db8::1".parse().unwrap(), &config, now)
+            .unwrap();
+        assert_eq!(
+            limiter
+                .admit_at("2001:db8::ffff:2".parse().unwrap(), &config, now)
+                .unwrap_err(),
+            Refusal::Connections
+        );
+        assert!(limiter
+            .admit_at("2001:db8:0:1::1".parse().unwrap(), &config, now)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_max_clients() {
+        let mut config = Config::default();
+        config.rate_limit = 10;
+        let limiter = Arc::new(Limiter::default());
+        let start = Instant::now();
+
+        // A client hopping between addresses can't grow the map
+        // without limit.
+        for n in 0..MAX_CLIENTS as u128 + 100 {
+            let ip = IpAddr::V6(Ipv6Addr::from(0x2001_0db8_u128 << 96 | n << 64));
+            let _ = limiter.admit_at(ip, &config, start);
+        }
+        assert_eq!(limiter.len(), MAX_CLIENTS);
+        let ip = "10.0.0.1".parse().unwrap();
+        assert_eq!(
+            limiter.admit_at(ip, &config, start).unwrap_err(),
+            Refusal::Clients
+        );
+
+        // Once their windows are up, they're forgotten.
+        let later = start + config.rate_window;
+        assert!(limiter.admit_at(ip, &config, later).is_ok());
+        assert_eq!(limiter.len(), 1);
+    }
+}
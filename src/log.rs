@@ -0,0 +1,330 @@
+//! Leveled logging for the server's status messages. Each server (or
+//! call to `render`) gets its own `Logger`, so quieting one doesn't
+//! quiet them all.
+//!
+//! By default errors and warnings go to stderr and everything else to
+//! stdout. A logger can also write everything to a file instead.
+
+use crate::Result;
+use std::{
+    ffi::CString,
+    fmt,
+    fs::File,
+    io::{self, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        io::{AsRawFd, FromRawFd},
+    },
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+/// A log file that can be opened again by name, e.g. after logrotate
+/// moves it. We keep its directory open from the start and open the
+/// file relative to that, so it still works after phd has chrooted
+/// somewhere the path doesn't lead.
+#[derive(Debug, Clone)]
+pub struct LogFile {
+    path: String,
+    dir: Arc<File>,
+    name: CString,
+}
+
+impl LogFile {
+    /// Get ready to log to `path`. Its directory has to exist.
+    pub fn new(path: &str) -> Result<LogFile> {
+        let err = |e: &dyn fmt::Display| format!("{}: {}", path, e);
+        let name = Path::new(path)
+            .file_name()
+            .ok_or_else(|| err(&"not a file"))?;
+        let dir = match Path::new(path).parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        Ok(LogFile {
+            path: path.into(),
+            dir: Arc::new(File::open(dir).map_err(|e| err(&e))?),
+            name: CString::new(name.as_bytes()).map_err(|e| err(&e))?,
+        })
+    }
+
+    /// The path we were given.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Open the file for appending, creating it if it isn't there.
+    pub fn open(&self) -> Result<File> {
+        let fd = unsafe {
+            libc::openat(
+                self.dir.as_raw_fd(),
+                self.name.as_ptr(),
+                libc::O_WRONLY | libc::O_APPEND | libc::O_CREAT | libc::O_CLOEXEC,
+                0o644 as libc::c_uint,
+            )
+        };
+        if fd < 0 {
+            let e = io::Error::last_os_error();
+            return Err(format!("{}: {}", self.path, e).into());
+        }
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+}
+
+/// How important a log message is. Loggers show messages at their
+/// level or below.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// Show nothing.
+    Off,
+    /// Something went wrong with a request or the server.
+    Error,
+    /// Something looks wrong, but we carried on.
+    Warn,
+    /// Normal traffic: connections, requests, replies.
+    Info,
+    /// Details useful when something isn't working.
+    Debug,
+    /// Everything.
+    Trace,
+}
+
+impl Level {
+    /// Parse a level name, e.g. `info`.
+    pub fn from(name: &str) -> Option<Level> {
+        Some(match name {
+            "off" => Level::Off,
+            "error" => Level::Error,
+            "warn" => Level::Warn,
+            "info" => Level::Info,
+            "debug" => Level::Debug,
+            "trace" => Level::Trace,
+            _ => return None,
+        })
+    }
+
+    /// One level more verbose.
+    pub fn louder(self) -> Level {
+        match self {
+            Level::Off => Level::Error,
+            Level::Error => Level::Warn,
+            Level::Warn => Level::Info,
+            Level::Info => Level::Debug,
+            Level::Debug | Level::Trace => Level::Trace,
+        }
+    }
+}
+
+/// Where log messages go.
+enum Target {
+    /// stderr for errors and warnings, stdout for the rest.
+    Std,
+    /// Everything goes into a file.
+    File(LogFile, Mutex<File>),
+}
+
+/// Writes log messages at or below a certain level.
+#[derive(Clone)]
+pub struct Logger {
+    level: Level,
+    target: Arc<Target>,
+}
+
+impl fmt::Debug for Logger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Logger({:?})", self.level)
+    }
+}
+
+impl Default for Logger {
+    fn default() -> Logger {
+        Logger::new(Level::Info)
+    }
+}
+
+impl Logger {
+    /// Log to stdout and stderr.
+    pub fn new(level: Level) -> Logger {
+        Logger {
+            level,
+            target: Arc::new(Target::Std),
+        }
+    }
+
+    /// A logger that doesn't log anything.
+    pub fn silent() -> Logger {
+        Logger::new(Level::Off)
+    }
+
+    /// Append log messages to the file at `path`.
+    pub fn to_file(level: Level, path: &str) -> Result<Logger> {
+        Logger::to_log_file(level, LogFile::new(path)?)
+    }
+
+    /// Append log messages to `log`.
+    pub fn to_log_file(level: Level, log: LogFile) -> Result<Logger> {
+        let file = log.open()?;
+        Ok(Logger {
+            level,
+            target: Arc::new(Target::File(log, Mutex::new(file))),
+        })
+    }
+
+    /// The file we're logging to, if any.
+    pub fn log_file(&self) -> Option<&LogFile> {
+        match &*self.target {
+            Target::File(log, _) => Some(log),
+            Target::Std => None,
+        }
+    }
+
+    /// Close and re-open our log file, if we have one.
+    pub fn reopen(&self) -> Result<()> {
+        if let Target::File(log, file) = &*self.target {
+            *file.lock().unwrap() = log.open()?;
+        }
+        Ok(())
+    }
+
+    /// The most verbose level this logger will show.
+    pub fn level(&self) -> Level {
+        self.level
+    }
+
+    /// Will messages at this level be shown?
+    pub fn enabled(&self, level: Level) -> bool {
+        level != Level::Off && level <= self.level
+    }
+
+    /// Write a message at the given level. Use the `error!`, `info!`,
+    /// etc. macros instead to avoid formatting messages that won't be
+    /// shown.
+    pub fn write(&self, level: Level, msg: &str) {
+        if !self.enabled(level) {
+            return;
+        }
+        match &*self.target {
+            Target::Std if level <= Level::Warn => {
+                let _ = writeln!(io::stderr(), "{}", msg);
+            }
+            Target::Std => {
+                let _ = writeln!(io::stdout(), "{}", msg);
+            }
+            Target::File(_, file) => {
+                let mut file = file.lock().unwrap();
+                let _ = writeln!(file, "{}", strip_colors(msg));
+            }
+        }
+    }
+}
+
+/// Remove terminal color codes from a message.
+fn strip_colors(msg: &str) -> String {
+    let mut out = String::with_capacity(msg.len());
+    let mut chars = msg.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // skip until the end of the escape sequence
+            while let Some(c) = chars.next() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Log a message at a given level: `log!(logger, Level::Info, "hi {}", x)`
+macro_rules! log {
+    ($log:expr, $level:expr, $($args:tt)+) => {
+        if $log.enabled($level) {
+            $log.write($level, &format!($($args)+));
+        }
+    };
+}
+
+/// Log an error: `error!(logger, "oops: {}", e)`
+macro_rules! error {
+    ($log:expr, $($args:tt)+) => { log!($log, $crate::log::Level::Error, $($args)+) };
+}
+
+/// Log a warning.
+macro_rules! warn {
+    ($log:expr, $($args:tt)+) => { log!($log, $crate::log::Level::Warn, $($args)+) };
+}
+
+/// Log normal traffic.
+macro_rules! info {
+    ($log:expr, $($args:tt)+) => { log!($log, $crate::log::Level::Info, $($args)+) };
+}
+
+/// Log details that help with debugging.
+macro_rules! debug {
+    ($log:expr, $($args:tt)+) => { log!($log, $crate::log::Level::Debug, $($args)+) };
+}
+
+/// Log everything.
+macro_rules! trace {
+    ($log:expr, $($args:tt)+) => { log!($log, $crate::log::Level::Trace, $($args)+) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use std::fs;
+
+    #[test]
+    fn test_levels() {
+        let log = Logger::new(Level::Info);
+        assert!(log.enabled(Level::Error));
+        assert!(log.enabled(Level::Info));
+        assert!(!log.enabled(Level::Debug));
+        assert!(!Logger::silent().enabled(Level::Error));
+        assert!(!log.enabled(Level::Off));
+        assert_eq!(Level::Info.louder().louder(), Level::Trace);
+    }
+
+    #[test]
+    fn test_reopen() {
+        let tmp = TempDir::new("log");
+        tmp.mkdir("logs");
+        let path = tmp.join("logs/phd.log");
+        let log = Logger::to_file(Level::Info, &path.to_string_lossy()).unwrap();
+        log.write(Level::Info, "one");
+        fs::rename(&path, tmp.join("logs/phd.log.1")).unwrap();
+        log.write(Level::Info, "two");
+        log.reopen().unwrap();
+        log.write(Level::Info, "three");
+        assert_eq!(
+            fs::read_to_string(tmp.join("logs/phd.log.1")).unwrap(),
+            "one\ntwo\n"
+        );
+        assert_eq!(fs::read_to_string(&path).unwrap(), "three\n");
+
+        // Even if the path stops leading there, as it would after a
+        // chroot, the file is reopened in the same directory.
+        fs::rename(tmp.join("logs"), tmp.join("moved")).unwrap();
+        fs::remove_file(tmp.join("moved/phd.log")).unwrap();
+        log.reopen().unwrap();
+        log.write(Level::Info, "four");
+        assert_eq!(
+            fs::read_to_string(tmp.join("moved/phd.log")).unwrap(),
+            "four\n"
+        );
+
+        assert!(LogFile::new("/").is_err());
+        assert!(LogFile::new(&tmp.join("nope/phd.log").to_string_lossy()).is_err());
+    }
+
+    #[test]
+    fn test_strip_colors() {
+        assert_eq!(
+            strip_colors("\x1b[92m┌ Connection\x1b[0m from \x1b[95m1.2.3.4"),
+            "┌ Connection from 1.2.3.4"
+        );
+    }
+}
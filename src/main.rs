@@ -1,22 +1,57 @@
-use phd;
-use std::process;
-
-const DEFAULT_BIND: &str = "[::]:7070";
-const DEFAULT_HOST: &str = "127.0.0.1";
-const DEFAULT_PORT: u16 = 7070;
+use phd::{
+    config::{
+        DEFAULT_BIND, DEFAULT_CACHE_SIZE, DEFAULT_GRACE, DEFAULT_HOST, DEFAULT_PORT, DEFAULT_QUEUE,
+        DEFAULT_STATS_SELECTOR, DEFAULT_WORKERS,
+    },
+    log::Level,
+    Config,
+};
+use std::{
+    fs,
+    io::{self, Write},
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    process,
+};
 
 fn main() {
     let args = std::env::args().skip(1).collect::<Vec<_>>();
     let mut args = args.iter();
-    let mut root = ".";
-    let mut addr = DEFAULT_BIND;
-    let mut host = DEFAULT_HOST;
-    let mut port = DEFAULT_PORT;
+    let mut settings = vec![];
+    let mut config_file = None;
     let mut render = "";
+    let mut preview = None;
+    let mut query = "";
+    let mut peer = None;
+    let mut output = None;
+    let mut test_dir = None;
+    let mut update = false;
+    let mut inetd = false;
+    let mut verbosity = None;
+
+    // Queue up a setting that takes a value.
+    macro_rules! setting {
+        ($key:expr) => {
+            if let Some(val) = args.next() {
+                settings.push(($key.to_string(), val.to_string()));
+            }
+        };
+    }
 
     while let Some(arg) = args.next() {
         match arg.as_ref() {
-            "--version" | "-v" | "-version" => return print_version(),
+            "--version" | "-V" | "-version" => return print_version(),
+            "--verbose" | "-verbose" | "-v" | "-vv" | "-vvv" => {
+                let mut level = verbosity.unwrap_or(Level::Info);
+                for _ in 0..arg.matches('v').count() {
+                    level = level.louder();
+                }
+                verbosity = Some(level);
+            }
+            "--quiet" | "-quiet" | "-q" => verbosity = Some(Level::Error),
+            "-qq" => verbosity = Some(Level::Off),
+            "--log" | "-log" => setting!("log"),
+            "--log-level" | "-log-level" => setting!("log-level"),
             "--help" | "-help" => return print_help(),
             "--no-color" | "-no-color" => phd::color::hide_colors(),
             "--render" | "-render" | "-r" => {
@@ -26,67 +61,145 @@ fn main() {
                     render = "/";
                 }
             }
-            "--bind" | "-b" | "-bind" => {
-                if let Some(a) = args.next() {
-                    addr = a
-                }
-            }
-            "--port" | "-p" | "-port" => {
-                if let Some(p) = args.next() {
-                    port = p
-                        .parse()
-                        .map_err(|_| {
-                            eprintln!("bad port: {}", p);
-                            process::exit(1)
-                        })
-                        .unwrap();
-                }
+            "--preview" | "-preview" | "-P" => {
+                preview = Some(args.next().map_or("/", |s| s.as_str()));
             }
+            "--query" | "-query" | "-Q" => query = args.next().map_or("", |s| s.as_str()),
+            "--peer" | "-peer" => peer = args.next(),
+            "--output" | "-output" | "-o" => output = args.next(),
+            "--test" | "-test" => test_dir = args.next(),
+            "--update" | "-update" => update = true,
+            "--inetd" | "-inetd" => inetd = true,
+            "--config" | "-config" | "-c" => config_file = args.next(),
+            "--bind" | "-b" | "-bind" => setting!("bind"),
+            "--port" | "-p" | "-port" => setting!("port"),
+            "--grace" | "-grace" => setting!("grace"),
+            "--access-log" | "-access-log" => setting!("access-log"),
+            "--access-format" | "-access-format" => setting!("access-format"),
+            "--user" | "-user" | "-u" => setting!("user"),
+            "--group" | "-group" | "-g" => setting!("group"),
+            "--chroot" | "-chroot" => settings.push(("chroot".into(), "true".into())),
+            "--metrics" | "-metrics" => setting!("metrics"),
+            "--backend" | "-backend" => setting!("backend"),
+            "--workers" | "-workers" => setting!("workers"),
+            "--queue" | "-queue" => setting!("queue"),
+            "--overflow" | "-overflow" => setting!("overflow"),
+            "--rate-limit" | "-rate-limit" => setting!("rate-limit"),
+            "--conn-limit" | "-conn-limit" => setting!("conn-limit"),
+            "--cache" | "-cache" => settings.push(("cache".into(), "true".into())),
+            "--cache-size" | "-cache-size" => setting!("cache-size"),
+            "--stats" | "-stats" => settings.push(("stats".into(), "true".into())),
             "-h" => {
-                if let Some(h) = args.next() {
-                    host = &h;
-                } else {
+                if args.len() == 0 {
                     return print_help();
                 }
+                setting!("host");
             }
-            "--host" | "-host" => {
-                if let Some(h) = args.next() {
-                    host = &h;
-                }
-            }
+            "--host" | "-host" => setting!("host"),
             _ => {
-                if let Some('-') = arg.chars().nth(0) {
+                if let Some('-') = arg.chars().next() {
                     eprintln!("unknown flag: {}", arg);
                     process::exit(1);
                 } else {
-                    root = &arg;
+                    settings.push(("root".into(), arg.into()));
                 }
             }
         }
     }
 
+    if let Some(level) = verbosity {
+        let name = format!("{:?}", level).to_lowercase();
+        settings.push(("log-level".into(), name));
+    }
+
     // https://no-color.org/
     if std::env::var("NO_COLOR").is_ok() {
         phd::color::hide_colors()
     }
 
-    // If port was given and socket wasn't, bind to that port.
-    let bind = if port != DEFAULT_PORT && addr == DEFAULT_BIND {
-        format!("[::]:{}", port).parse().unwrap()
-    } else {
-        addr.parse().unwrap()
+    let config = match Config::new(config_file.map(|s| s.as_ref()), settings) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
     };
 
     if !render.is_empty() {
-        return match phd::server::render(host, port, root, &render) {
-            Ok(out) => print!("{}", out),
-            Err(e) => eprintln!("{}", e),
-        };
+        if let Err(e) = render_to(&config, render, query, peer, output) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+        return;
     }
 
-    if let Err(e) = phd::server::start(bind, host, port, root) {
+    if let Some(dir) = test_dir {
+        let stdout = io::stdout();
+        match phd::snapshot::run(&config, Path::new(dir), update, stdout.lock()) {
+            Ok(summary) if summary.failed == 0 => return,
+            Ok(_) => process::exit(1),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    if let Some(selector) = preview {
+        let stdin = std::io::stdin();
+        if let Err(e) = phd::preview::run(&config, selector, stdin.lock(), std::io::stdout()) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if inetd {
+        // stderr may be the client's socket, so errors only go to the
+        // log file.
+        if phd::server::serve_stdio(config).is_err() {
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Err(e) = phd::server::start(config) {
         eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+/// Render a selector and write the raw response to `output`, or to
+/// stdout.
+fn render_to(
+    config: &Config,
+    selector: &str,
+    query: &str,
+    peer: Option<&String>,
+    output: Option<&String>,
+) -> phd::Result<()> {
+    let peer = match peer {
+        Some(addr) => Some(parse_peer(addr).ok_or_else(|| format!("bad peer address: {}", addr))?),
+        None => None,
+    };
+    let out = phd::server::render(config, selector, query, peer)?;
+    match output {
+        Some(path) if path != "-" => fs::write(path, out)?,
+        _ => {
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            stdout.write_all(&out)?;
+            stdout.flush()?;
+        }
     }
+    Ok(())
+}
+
+/// Parse `IP` or `IP:PORT`.
+fn parse_peer(addr: &str) -> Option<SocketAddr> {
+    addr.parse()
+        .ok()
+        .or_else(|| Some(SocketAddr::new(addr.parse::<IpAddr>().ok()?, 0)))
 }
 
 fn print_help() {
@@ -98,15 +211,43 @@ fn print_help() {
 Options:
 
     -r, --render SELECTOR  Render and print SELECTOR to stdout only.
+    -Q, --query QUERY      Search query to send with --render.
+    --peer ADDRESS         Client address to pretend --render came from.
+    -o, --output FILE      Write --render's output to FILE.
+    -P, --preview SELECTOR Browse the site in the terminal from SELECTOR.
+    --test DIR             Check the site against the .snap files in DIR.
+    --update               With --test, record responses that changed.
+    -c, --config FILE      Read settings from FILE. Reloaded on SIGHUP.
+    --inetd                Serve one request from stdin to stdout.
     -h, --host HOST        Hostname for links. [Default: {host}]
     -p, --port PORT        Port for links. [Default: {port}]
     -b, --bind ADDRESS     Socket address to bind to. [Default: {bind}]
+    -u, --user USER        Switch to USER after binding.
+    -g, --group GROUP      Switch to GROUP after binding.
+    --chroot               Chroot into the root directory after binding.
+    --grace SECONDS        Time to let requests finish on shutdown. [Default: {grace}]
+    --access-log FILE      Log each request to FILE. Use - for stdout.
+    --access-format FMT    Access log format: common or json. [Default: common]
+    --stats                Serve server statistics at {stats}.
+    --metrics ADDRESS      Serve Prometheus metrics over HTTP on ADDRESS.
+    --backend BACKEND      threads, or async if built with it. [Default: threads]
+    --workers N            Requests to handle at once. [Default: {workers}]
+    --queue N              Connections that can wait for a worker. [Default: {queue}]
+    --overflow POLICY      When the queue is full: reject or block. [Default: reject]
+    --rate-limit N         Max requests per client IP per minute.
+    --conn-limit N         Max open connections per client IP.
+    --cache                Keep rendered menus in memory.
+    --cache-size SIZE      Most memory for cached menus. [Default: {cache_size}M]
+    -v, --verbose          Log more. Use -vv to log everything.
+    -q, --quiet            Only log errors. Use -qq to log nothing.
+    --log FILE             Write log messages to FILE.
+    --log-level LEVEL      error, warn, info, debug, or trace. [Default: info]
     --no-color             Don't show colors in log messages.
 
 Other flags:
 
     -h, --help      Print this screen.
-    -v, --version   Print phd version.
+    -V, --version   Print phd version.
 
 Examples:
 
@@ -115,10 +256,19 @@ Examples:
     phd -h gopher.com   # Serve current directory over port 7070
                         # using hostname 'gopher.com'
     phd -r / ./site     # Render local gopher site to stdout.
+    phd -P / ./site     # Browse local gopher site in the terminal.
+    phd -c phd.conf     # Serve using the settings in phd.conf
+    sudo phd -p 70 -u nobody --chroot /srv/gopher
+                        # Serve port 70, then drop root privileges
 ",
         host = DEFAULT_HOST,
         port = DEFAULT_PORT,
         bind = DEFAULT_BIND,
+        stats = DEFAULT_STATS_SELECTOR,
+        workers = DEFAULT_WORKERS,
+        queue = DEFAULT_QUEUE,
+        grace = DEFAULT_GRACE,
+        cache_size = DEFAULT_CACHE_SIZE >> 20,
     );
 }
 
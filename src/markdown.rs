@@ -0,0 +1,382 @@
+//! Turn Markdown into something a Gopher menu can show. Headings and
+//! paragraphs are wrapped into lines of text, code blocks are kept as
+//! they are, and links are pulled out onto their own lines so clients
+//! can follow them:
+//!
+//! ```text
+//! # Hello
+//!
+//! See [my phlog](/phlog) or [the web](https://example.com).
+//! ```
+//!
+//! becomes the text `Hello`, `=====`, a blank line, and `See my phlog
+//! or the web.`, followed by links to `/phlog` and
+//! `https://example.com`.
+//!
+//! This only covers the common parts of Markdown. Anything it doesn't
+//! understand is shown as written.
+
+use unicode_width::UnicodeWidthStr;
+
+/// A line of converted Markdown. Neither kind has tabs in its text.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Line {
+    /// Text to show as-is.
+    Text(String),
+    /// Something to link to: a path, a URL, or an image.
+    Link {
+        /// What the link says.
+        text: String,
+        /// Where it goes, exactly as written.
+        target: String,
+    },
+}
+
+/// A block of lines that wrap together, like a paragraph or one item
+/// in a list.
+struct Block {
+    /// Put before the first line, e.g. `- ` for a list item.
+    first: String,
+    /// Put before the other lines, e.g. `  ` for a list item.
+    rest: String,
+    /// The text so far, with lines joined by spaces.
+    text: String,
+}
+
+/// Convert Markdown to lines at most `width` columns wide. Code
+/// blocks aren't wrapped.
+pub fn convert(markdown: &str, width: usize) -> Vec<Line> {
+    let mut out = vec![];
+    let mut block: Option<Block> = None;
+    let mut fence: Option<&str> = None;
+
+    for line in markdown.lines() {
+        let line = line.trim_end_matches('\r');
+        let trimmed = line.trim();
+
+        if let Some(marker) = fence {
+            if trimmed.starts_with(marker) {
+                fence = None;
+            } else {
+                out.push(Line::Text(detab(line)));
+            }
+            continue;
+        }
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            flush(&mut out, block.take(), width);
+            fence = Some(&trimmed[..3]);
+            continue;
+        }
+        if trimmed.is_empty() {
+            flush(&mut out, block.take(), width);
+            out.push(Line::Text(String::new()));
+            continue;
+        }
+        if block.is_none() && (line.starts_with("    ") || line.starts_with('\t')) {
+            // Indented code.
+            out.push(Line::Text(detab(line)));
+            continue;
+        }
+        if let Some((level, title)) = heading(trimmed) {
+            flush(&mut out, block.take(), width);
+            let text = detab(&inline(title, &mut vec![]));
+            let underline = match level {
+                1 => Some('='),
+                2 => Some('-'),
+                _ => None,
+            };
+            let len = text.width();
+            out.push(Line::Text(text));
+            if let Some(c) = underline {
+                out.push(Line::Text(c.to_string().repeat(len)));
+            }
+            continue;
+        }
+        if is_rule(trimmed) {
+            flush(&mut out, block.take(), width);
+            out.push(Line::Text("-".repeat(width.min(20))));
+            continue;
+        }
+        if let Some(rest) = trimmed.strip_prefix('>') {
+            let rest = rest.trim_start();
+            match &mut block {
+                Some(b) if b.first == "> " => push_text(b, rest),
+                _ => {
+                    flush(&mut out, block.take(), width);
+                    block = Some(Block {
+                        first: "> ".into(),
+                        rest: "> ".into(),
+                        text: rest.into(),
+                    });
+                }
+            }
+            continue;
+        }
+        if let Some((bullet, rest)) = list_item(trimmed) {
+            flush(&mut out, block.take(), width);
+            let indent = " ".repeat(bullet.chars().count());
+            block = Some(Block {
+                first: bullet,
+                rest: indent,
+                text: rest.into(),
+            });
+            continue;
+        }
+        match &mut block {
+            Some(b) => push_text(b, trimmed),
+            None => {
+                block = Some(Block {
+                    first: String::new(),
+                    rest: String::new(),
+                    text: trimmed.into(),
+                })
+            }
+        }
+    }
+    flush(&mut out, block.take(), width);
+
+    // Drop blank lines at the start and end, and runs of them.
+    let mut lines: Vec<Line> = vec![];
+    for line in out {
+        let blank = line == Line::Text(String::new());
+        if blank && lines.last().is_none_or(|l| *l == Line::Text(String::new())) {
+            continue;
+        }
+        lines.push(line);
+    }
+    while lines.last() == Some(&Line::Text(String::new())) {
+        lines.pop();
+    }
+    lines
+}
+
+/// Add a line to a block of text.
+fn push_text(block: &mut Block, text: &str) {
+    if !block.text.is_empty() {
+        block.text.push(' ');
+    }
+    block.text.push_str(text);
+}
+
+/// Wrap up a block: its text, then any links in it. A block that's
+/// nothing but a link just becomes the link.
+fn flush(out: &mut Vec<Line>, block: Option<Block>, width: usize) {
+    let block = match block {
+        Some(block) => block,
+        None => return,
+    };
+    let mut links = vec![];
+    let text = inline(&block.text, &mut links);
+    let only_link =
+        matches!(&links[..], [Line::Link { text: link, .. }] if text.trim() == link.trim());
+    if !only_link {
+        for line in wrap(&text, width, &block.first, &block.rest) {
+            out.push(Line::Text(line));
+        }
+    }
+    out.extend(links);
+}
+
+/// `# Title` => (1, "Title")
+fn heading(line: &str) -> Option<(usize, &str)> {
+    let level = line.chars().take_while(|&c| c == '#').count();
+    let rest = &line[level..];
+    if (1..=6).contains(&level) && (rest.is_empty() || rest.starts_with(' ')) {
+        Some((level, rest.trim().trim_end_matches('#').trim_end()))
+    } else {
+        None
+    }
+}
+
+/// `---`, `***`, or `___`, maybe with spaces.
+fn is_rule(line: &str) -> bool {
+    let chars: Vec<char> = line.chars().filter(|c| !c.is_whitespace()).collect();
+    chars.len() >= 3
+        && ['-', '*', '_']
+            .iter()
+            .any(|&m| chars.iter().all(|&c| c == m))
+}
+
+/// `- item` => ("- ", "item"), `12. item` => ("12. ", "item")
+fn list_item(line: &str) -> Option<(String, &str)> {
+    for bullet in &["- ", "* ", "+ "] {
+        if let Some(rest) = line.strip_prefix(bullet) {
+            return Some((bullet.to_string(), rest));
+        }
+    }
+    let digits = line.chars().take_while(|c| c.is_ascii_digit()).count();
+    if digits > 0 {
+        let rest = &line[digits..];
+        if let Some(rest) = rest.strip_prefix(". ").or_else(|| rest.strip_prefix(") ")) {
+            return Some((format!("{} ", &line[..digits + 1]), rest));
+        }
+    }
+    None
+}
+
+/// Replace links in `text` with what they say, adding them to `links`.
+/// Handles `[text](target)`, `![alt](src)`, and `<https://autolinks>`.
+fn inline(text: &str, links: &mut Vec<Line>) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        if let Some((label, target, len)) = link_at(rest) {
+            out.push_str(&label);
+            links.push(Line::Link {
+                text: if label.is_empty() {
+                    target.clone()
+                } else {
+                    detab(&label)
+                },
+                target,
+            });
+            rest = &rest[len..];
+        } else {
+            let c = rest.chars().next().unwrap_or(' ');
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    out
+}
+
+/// If `s` starts with a link, return its text, its target, and how
+/// many bytes of `s` it took up.
+fn link_at(s: &str) -> Option<(String, String, usize)> {
+    if let Some(rest) = s.strip_prefix('<') {
+        let end = rest.find('>')?;
+        let url = &rest[..end];
+        if url.contains("://") && !url.contains(char::is_whitespace) {
+            return Some((url.into(), url.into(), end + 2));
+        }
+        return None;
+    }
+    let skip = if s.starts_with("![") { 1 } else { 0 };
+    let rest = s[skip..].strip_prefix('[')?;
+    let close = rest.find("](")?;
+    let label = &rest[..close];
+    let after = &rest[close + 2..];
+    let end = after.find(')')?;
+    // Drop any title: [text](url "title")
+    let target = after[..end].split_whitespace().next().unwrap_or("");
+    if target.is_empty() {
+        return None;
+    }
+    let len = skip + 1 + close + 2 + end + 1;
+    Some((inline(label, &mut vec![]), target.into(), len))
+}
+
+/// Word wrap `text` to `width` columns, starting the first line with
+/// `first` and the rest with `rest`. Wide characters like CJK take up
+/// two columns. Words longer than a line get a line to themselves.
+pub(crate) fn wrap(text: &str, width: usize, first: &str, rest: &str) -> Vec<String> {
+    let mut lines = vec![];
+    let mut line = first.to_string();
+    let mut empty = true;
+    for word in text.split_whitespace() {
+        let len = line.width() + word.width() + if empty { 0 } else { 1 };
+        if !empty && len > width {
+            lines.push(line);
+            line = rest.to_string();
+            empty = true;
+        }
+        if !empty {
+            line.push(' ');
+        }
+        line.push_str(word);
+        empty = false;
+    }
+    if !empty || lines.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+/// Tabs would break a Gopher menu, so swap them for spaces.
+pub(crate) fn detab(line: &str) -> String {
+    line.replace('\t', "    ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(s: &str) -> Line {
+        Line::Text(s.into())
+    }
+
+    fn link(text: &str, target: &str) -> Line {
+        Line::Link {
+            text: text.into(),
+            target: target.into(),
+        }
+    }
+
+    #[test]
+    fn test_convert() {
+        let md =
+            "# Hello\n\nSee [my phlog](/phlog) or\n[the web](https://example.com \"Web\").\n\n\n\
+                  - [Post](post.md)\n- A longer item that wraps\n\n> quoted\n> text\n\n\
+                  ```\ncode\tblock  here\n```\n\n![cat](cat.png)\n";
+        assert_eq!(
+            convert(md, 16),
+            vec![
+                text("Hello"),
+                text("====="),
+                text(""),
+                text("See my phlog or"),
+                text("the web."),
+                link("my phlog", "/phlog"),
+                link("the web", "https://example.com"),
+                text(""),
+                link("Post", "post.md"),
+                text("- A longer item"),
+                text("  that wraps"),
+                text(""),
+                text("> quoted text"),
+                text(""),
+                text("code    block  here"),
+                text(""),
+                link("cat", "cat.png"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_inline() {
+        let mut links = vec![];
+        assert_eq!(
+            inline("a [b](c) <https://d.example> [e] (f)", &mut links),
+            "a b https://d.example [e] (f)"
+        );
+        assert_eq!(
+            links,
+            vec![
+                link("b", "c"),
+                link("https://d.example", "https://d.example")
+            ]
+        );
+        assert_eq!(heading("## Two ##"), Some((2, "Two")));
+        assert_eq!(heading("#hashtag"), None);
+        assert_eq!(
+            convert("# A\tB\n\n[c\td](e) <https://f\tg>\n", 80),
+            vec![
+                text("A    B"),
+                text("======"),
+                text(""),
+                text("c d <https://f g>"),
+                link("c    d", "e"),
+            ]
+        );
+        assert!(is_rule("* * *"));
+        assert_eq!(list_item("10. ten"), Some(("10. ".into(), "ten")));
+    }
+
+    #[test]
+    fn test_wrap() {
+        assert_eq!(wrap("aa bb cc", 5, "", ""), vec!["aa bb", "cc"]);
+        assert_eq!(wrap("toolongword x", 5, "", ""), vec!["toolongword", "x"]);
+        assert_eq!(wrap("", 5, "> ", "> "), vec!["> "]);
+        assert_eq!(wrap("日本 語", 5, "", ""), vec!["日本", "語"]);
+    }
+}
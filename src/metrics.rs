@@ -0,0 +1,305 @@
+//! Prometheus metrics, served as plain text over HTTP on their own
+//! port so they can be kept off the public internet:
+//!
+//! ```text
+//! $ phd --metrics 127.0.0.1:9070 ./site
+//! $ curl http://127.0.0.1:9070/metrics
+//! ```
+
+use crate::{
+    color,
+    log::Logger,
+    stats::{Histogram, Stats},
+    Result,
+};
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Give up on scrapers that take longer than this to send a request.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Ignore requests with more header lines than this.
+const MAX_HEADERS: usize = 100;
+
+/// Stop reading a request after this many bytes.
+const MAX_REQUEST_SIZE: u64 = 16 * 1024;
+
+/// Most requests to answer at once. Scrapers only need one.
+const MAX_CONNECTIONS: usize = 8;
+
+/// Answer metrics requests on `listener` until the process exits.
+/// Each one gets its own thread, so a slow client can't hold up the
+/// next scrape.
+pub fn serve(listener: TcpListener, stats: Arc<Stats>, log: Logger) {
+    let open = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!(log, "{}» Metrics: {}{}", color::Red, e, color::Reset);
+                continue;
+            }
+        };
+        if open.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+            open.fetch_sub(1, Ordering::SeqCst);
+            warn!(
+                log,
+                "{}» Metrics: too many connections{}",
+                color::Red,
+                color::Reset
+            );
+            continue;
+        }
+        let (stats, log, open) = (Arc::clone(&stats), log.clone(), Arc::clone(&open));
+        thread::spawn(move || {
+            if let Err(e) = respond(stream, &stats) {
+                warn!(log, "{}» Metrics: {}{}", color::Red, e, color::Reset);
+            }
+            open.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+/// Reads from a stream until a deadline, however slowly the other end
+/// sends.
+struct Deadline<'a> {
+    stream: &'a TcpStream,
+    until: Instant,
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.until.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "request too slow"));
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        (&*self.stream).read(buf)
+    }
+}
+
+/// Answer a single HTTP request.
+fn respond(mut stream: TcpStream, stats: &Stats) -> Result<()> {
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let deadline = Deadline {
+        stream: &stream,
+        until: Instant::now() + TIMEOUT,
+    };
+    let mut reader = BufReader::new(deadline.take(MAX_REQUEST_SIZE));
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    for _ in 0..MAX_HEADERS {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) | (Some("GET"), Some("/")) => ("200 OK", render(stats)),
+        (Some("GET"), _) => ("404 Not Found", "Not Found\n".into()),
+        _ => ("405 Method Not Allowed", "Method Not Allowed\n".into()),
+    };
+    write!(
+        stream,
+        "HTTP/1.0 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Format the server's counters in the Prometheus text format.
+pub fn render(stats: &Stats) -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, lines: Vec<String>| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for line in lines {
+            let _ = writeln!(out, "{}", line);
+        }
+    };
+
+    metric(
+        "phd_uptime_seconds",
+        "gauge",
+        "Seconds since the server started.",
+        vec![format!("phd_uptime_seconds {}", stats.uptime().as_secs())],
+    );
+    metric(
+        "phd_requests_total",
+        "counter",
+        "Requests answered, by kind of response.",
+        stats
+            .kinds()
+            .into_iter()
+            .map(|(kind, n)| format!("phd_requests_total{{kind=\"{}\"}} {}", kind, n))
+            .collect(),
+    );
+    metric(
+        "phd_not_found_total",
+        "counter",
+        "Requests for selectors that don't exist.",
+        vec![format!(
+            "phd_not_found_total {}",
+            stats
+                .kinds()
+                .into_iter()
+                .find(|(kind, _)| *kind == "NOTFOUND")
+                .map_or(0, |(_, n)| n)
+        )],
+    );
+    metric(
+        "phd_response_bytes_total",
+        "counter",
+        "Bytes sent to clients.",
+        vec![format!("phd_response_bytes_total {}", stats.bytes())],
+    );
+    metric(
+        "phd_response_size_bytes",
+        "histogram",
+        "Size of each response.",
+        histogram("phd_response_size_bytes", stats.response_bytes()),
+    );
+    metric(
+        "phd_script_runs_total",
+        "counter",
+        "Executable .gph files run.",
+        vec![format!("phd_script_runs_total {}", stats.scripts())],
+    );
+    metric(
+        "phd_script_failures_total",
+        "counter",
+        "Executable .gph files that failed to start or exited with an error.",
+        vec![format!(
+            "phd_script_failures_total {}",
+            stats.script_failures()
+        )],
+    );
+    metric(
+        "phd_script_duration_seconds",
+        "histogram",
+        "How long executable .gph files took to run.",
+        histogram("phd_script_duration_seconds", stats.script_seconds()),
+    );
+    metric(
+        "phd_active_connections",
+        "gauge",
+        "Connections being handled right now.",
+        vec![format!("phd_active_connections {}", stats.busy())],
+    );
+    metric(
+        "phd_max_workers",
+        "gauge",
+        "Most connections that can be handled at once.",
+        vec![format!("phd_max_workers {}", stats.workers())],
+    );
+    out
+}
+
+/// The `_bucket`, `_sum`, and `_count` lines for a histogram.
+fn histogram(name: &str, hist: &Histogram) -> Vec<String> {
+    let mut lines: Vec<_> = hist
+        .buckets()
+        .into_iter()
+        .map(|(le, n)| format!("{}_bucket{{le=\"{}\"}} {}", name, le, n))
+        .collect();
+    lines.push(format!("{}_bucket{{le=\"+Inf\"}} {}", name, hist.count()));
+    lines.push(format!("{}_sum {}", name, hist.sum()));
+    lines.push(format!("{}_count {}", name, hist.count()));
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let stats = Stats::default();
+        stats.set_workers(10);
+        stats.begin();
+        stats.record("/", None, "DIR", 300);
+        stats.record("/nope", None, "NOTFOUND", 20);
+        stats.script(Duration::from_millis(30), false);
+
+        let out = render(&stats);
+        assert!(out.contains("# TYPE phd_requests_total counter\n"));
+        assert!(out.contains("phd_requests_total{kind=\"DIR\"} 1\n"));
+        assert!(out.contains("phd_not_found_total 1\n"));
+        assert!(out.contains("phd_response_bytes_total 320\n"));
+        assert!(out.contains("phd_response_size_bytes_bucket{le=\"256\"} 1\n"));
+        assert!(out.contains("phd_response_size_bytes_bucket{le=\"1024\"} 2\n"));
+        assert!(out.contains("phd_script_failures_total 1\n"));
+        assert!(out.contains("phd_script_duration_seconds_bucket{le=\"0.025\"} 0\n"));
+        assert!(out.contains("phd_script_duration_seconds_bucket{le=\"0.05\"} 1\n"));
+        assert!(out.contains("phd_script_duration_seconds_count 1\n"));
+        assert!(out.contains("phd_active_connections 1\n"));
+        assert!(out.contains("phd_max_workers 10\n"));
+    }
+
+    fn scrape(addr: std::net::SocketAddr) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.0\r\n\r\n").unwrap();
+        let mut out = String::new();
+        stream.read_to_string(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_slow_clients() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve(listener, Arc::new(Stats::default()), Logger::silent()));
+
+        // one client says nothing, another never ends its line
+        let _quiet = TcpStream::connect(addr).unwrap();
+        let mut long = TcpStream::connect(addr).unwrap();
+        long.write_all(&[b'a'; MAX_REQUEST_SIZE as usize]).unwrap();
+
+        let start = Instant::now();
+        assert!(scrape(addr).starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(start.elapsed() < TIMEOUT);
+
+        // the long line is cut off and answered without waiting
+        let mut out = String::new();
+        long.read_to_string(&mut out).unwrap();
+        assert!(out.starts_with("HTTP/1.0 405"), "{:?}", out);
+    }
+
+    #[test]
+    fn test_deadline() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        thread::spawn(move || {
+            for _ in 0..20 {
+                if client.write_all(b"G").is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+
+        let start = Instant::now();
+        let mut reader = BufReader::new(Deadline {
+            stream: &stream,
+            until: start + Duration::from_millis(200),
+        });
+        let err = reader.read_line(&mut String::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_millis(500));
+    }
+}
@@ -0,0 +1,295 @@
+//! A tiny Gopher client for checking a site while you write it. Pages
+//! are rendered straight from disk with `server::render`, so there's
+//! no server to start and every page shows your latest changes:
+//!
+//! ```text
+//! $ phd --preview / ./site
+//! ── /
+//!     Welcome to my gopher hole!
+//!   1. phlog                        [menu] /phlog
+//!   2. about.txt                    [text] /about.txt
+//! > 2
+//! ```
+
+use crate::{color, gopher::Type, server, Config, Result};
+use std::io::{BufRead, Write};
+
+const HELP: &str = "Commands:
+  N            follow link number N
+  /SELECTOR    go to SELECTOR
+  b            go back
+  r            reload
+  q            quit";
+
+/// A line in a Gopher menu.
+#[derive(Debug, Clone, PartialEq)]
+struct Item {
+    typ: Type,
+    name: String,
+    selector: String,
+    host: String,
+    port: String,
+}
+
+impl Item {
+    /// Parse a menu line. Returns None for lines that aren't items.
+    fn parse(line: &str) -> Option<Item> {
+        let mut chars = line.chars();
+        let typ = Type::from(chars.next()?)?;
+        let mut parts = chars.as_str().split('\t');
+        Some(Item {
+            typ,
+            name: parts.next().unwrap_or("").into(),
+            selector: parts.next().unwrap_or("").into(),
+            host: parts.next().unwrap_or("").into(),
+            port: parts.next().unwrap_or("").into(),
+        })
+    }
+
+    /// Where this link points, if it's somewhere we can't go.
+    fn elsewhere(&self, config: &Config) -> Option<String> {
+        if let Some(url) = self.selector.strip_prefix("URL:") {
+            return Some(url.into());
+        }
+        if self.host != config.host || self.port != config.port.to_string() {
+            return Some(format!(
+                "gopher://{}:{}/{}{}",
+                self.host, self.port, self.typ, self.selector
+            ));
+        }
+        None
+    }
+}
+
+/// A page we've visited.
+#[derive(Debug, Clone)]
+struct Page {
+    selector: String,
+    query: String,
+    /// How to show it, if we know.
+    typ: Option<Type>,
+}
+
+/// Browse the site in `config` from `selector`, reading commands from
+/// `input` and writing pages to `out`, until the user quits or input
+/// runs out.
+pub fn run<R: BufRead, W: Write>(
+    config: &Config,
+    selector: &str,
+    input: R,
+    mut out: W,
+) -> Result<()> {
+    let mut history = vec![Page {
+        selector: selector.into(),
+        query: String::new(),
+        typ: None,
+    }];
+    let mut lines = input.lines();
+    let mut links = show(config, &history[history.len() - 1], &mut out);
+
+    loop {
+        write!(out, "{}> {}", color::Bold, color::Reset)?;
+        out.flush()?;
+        let cmd = match lines.next() {
+            Some(line) => line?,
+            None => {
+                writeln!(out)?;
+                return Ok(());
+            }
+        };
+        let cmd = cmd.trim();
+        let next = match cmd {
+            "" => continue,
+            "q" | "quit" => return Ok(()),
+            "?" | "h" | "help" => {
+                writeln!(out, "{}", HELP)?;
+                continue;
+            }
+            "r" | "reload" => None,
+            "b" | "back" => {
+                if history.len() > 1 {
+                    history.pop();
+                } else {
+                    writeln!(out, "Nowhere to go back to.")?;
+                    continue;
+                }
+                None
+            }
+            _ if cmd.starts_with('/') => Some(Page {
+                selector: cmd.into(),
+                query: String::new(),
+                typ: None,
+            }),
+            _ => {
+                let item = match cmd.parse::<usize>() {
+                    Ok(n) if n > 0 && n <= links.len() => &links[n - 1],
+                    _ => {
+                        writeln!(out, "Unknown command. Type ? for help.")?;
+                        continue;
+                    }
+                };
+                if let Some(url) = item.elsewhere(config) {
+                    writeln!(out, "Not on this site: {}", url)?;
+                    continue;
+                }
+                let mut query = String::new();
+                if item.typ == Type::Search {
+                    write!(out, "{}: ", item.name)?;
+                    out.flush()?;
+                    query = match lines.next() {
+                        Some(line) => line?,
+                        None => return Ok(()),
+                    };
+                }
+                Some(Page {
+                    selector: item.selector.clone(),
+                    query,
+                    typ: Some(item.typ),
+                })
+            }
+        };
+        if let Some(page) = next {
+            history.push(page);
+        }
+        links = show(config, &history[history.len() - 1], &mut out);
+    }
+}
+
+/// Render and print a page, returning the links on it in order. If it
+/// can't be rendered, say why and carry on.
+fn show<W: Write>(config: &Config, page: &Page, out: &mut W) -> Vec<Item> {
+    match render_page(config, page, out) {
+        Ok(links) => links,
+        Err(e) => {
+            writeln!(out, "{}{}{}", color::Red, e, color::Reset);
+            vec![]
+        }
+    }
+}
+
+fn render_page<W: Write>(config: &Config, page: &Page, out: &mut W) -> Result<Vec<Item>> {
+    let bytes = server::render(config, &page.selector, &page.query, None)?;
+    let body = String::from_utf8_lossy(&bytes);
+    writeln!(
+        out,
+        "{}── {}{}{}",
+        color::Blue,
+        color::Bold,
+        if page.selector.is_empty() {
+            "/"
+        } else {
+            &page.selector
+        },
+        color::Reset
+    )?;
+
+    let typ = page.typ.unwrap_or_else(|| guess_type(&body));
+    let mut links = vec![];
+    if typ == Type::Menu || typ == Type::Search {
+        for line in body.lines() {
+            let item = match Item::parse(line.trim_end_matches('\r')) {
+                Some(item) => item,
+                None => continue,
+            };
+            match item.typ {
+                Type::Info => writeln!(out, "    {}", item.name)?,
+                Type::Error => writeln!(out, "    {}{}{}", color::Red, item.name, color::Reset)?,
+                _ => {
+                    links.push(item.clone());
+                    let target = item
+                        .elsewhere(config)
+                        .unwrap_or_else(|| item.selector.clone());
+                    writeln!(
+                        out,
+                        "{:>3}. {:<28} {}[{}] {}{}",
+                        links.len(),
+                        item.name,
+                        color::Blue,
+                        type_name(item.typ),
+                        target,
+                        color::Reset
+                    )?
+                }
+            }
+        }
+    } else if typ.is_download() {
+        writeln!(out, "({} file, {} bytes)", type_name(typ), bytes.len())?;
+    } else {
+        write!(out, "{}", body)?;
+        if !body.ends_with('\n') {
+            writeln!(out)?;
+        }
+    }
+    Ok(links)
+}
+
+/// Is this a menu or a text file? Every line of a menu is an item,
+/// apart from the `.` at the end.
+fn guess_type(body: &str) -> Type {
+    let is_menu = !body.is_empty()
+        && body
+            .lines()
+            .map(|line| line.trim_end_matches('\r'))
+            .all(|line| line == "." || line.matches('\t').count() >= 3);
+    if is_menu {
+        Type::Menu
+    } else {
+        Type::Text
+    }
+}
+
+/// Human friendly name for a Gopher type.
+fn type_name(typ: Type) -> &'static str {
+    match typ {
+        Type::Text => "text",
+        Type::Menu => "menu",
+        Type::Search => "search",
+        Type::HTML => "html",
+        Type::Telnet | Type::Telnet3270 => "telnet",
+        Type::GIF | Type::Image | Type::PNG => "image",
+        Type::Sound => "sound",
+        Type::Document => "document",
+        Type::Error => "error",
+        _ => "binary",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn test_parse_item() {
+        let item = Item::parse("1phlog\t/phlog\t127.0.0.1\t7070").unwrap();
+        assert_eq!(item.typ, Type::Menu);
+        assert_eq!(item.selector, "/phlog");
+        let config = Config::default();
+        assert_eq!(item.elsewhere(&config), None);
+        let item = Item::parse("hsite\tURL:https://example.com\tx\t70").unwrap();
+        assert_eq!(
+            item.elsewhere(&config).as_deref(),
+            Some("https://example.com")
+        );
+        assert!(Item::parse("").is_none());
+        assert_eq!(guess_type("iHi\t\tx\t70\r\n.\r\n"), Type::Menu);
+        assert_eq!(guess_type(".\r\n"), Type::Menu);
+        assert_eq!(guess_type("just text\n"), Type::Text);
+    }
+
+    #[test]
+    fn test_preview() {
+        let root = TempDir::new("preview");
+        root.write("docs/hello.txt", "Hello, world!\n");
+        let config = root.config();
+
+        let mut out = vec![];
+        run(&config, "/", &b"1\n1\nb\n9\nq\n"[..], &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("  1. docs"));
+        assert!(out.contains("[menu] /docs"));
+        assert!(out.contains("[text] /docs/hello.txt"));
+        assert!(out.contains("Hello, world!\n"));
+        assert!(out.contains("Unknown command"));
+    }
+}
@@ -0,0 +1,112 @@
+//! Dropping root privileges once we've bound to a low port like 70,
+//! so `.gph` scripts don't run as root. Optionally chroot into the
+//! site root first.
+
+use crate::{Config, Result};
+use std::{ffi::CString, fs, io, mem, ptr};
+
+/// Size of the scratch buffer for getpwnam_r(3) and getgrnam_r(3).
+const LOOKUP_BUF_SIZE: usize = 16 * 1024;
+
+/// Chroot into the site root and switch to the configured user and
+/// group, if any. Returns an error if any step fails, or if we're
+/// somehow still able to regain root afterwards.
+pub fn drop_privileges(config: &Config) -> Result<()> {
+    // Look these up before chrooting, while /etc/passwd is visible.
+    let user = match &config.user {
+        Some(name) => Some(lookup_user(name)?),
+        None => None,
+    };
+    let gid = match (&config.group, user) {
+        (Some(name), _) => Some(lookup_group(name)?),
+        (None, Some((_, gid))) => Some(gid),
+        (None, None) => None,
+    };
+
+    if config.chroot {
+        let root = fs::canonicalize(&config.root)?;
+        let root = CString::new(root.to_string_lossy().as_bytes())?;
+        check("chroot", unsafe { libc::chroot(root.as_ptr()) })?;
+        check("chdir", unsafe { libc::chdir(b"/\0".as_ptr() as *const _) })?;
+    }
+
+    if let Some(gid) = gid {
+        check("setgroups", unsafe { libc::setgroups(1, &gid) })?;
+        check("setgid", unsafe { libc::setgid(gid) })?;
+    }
+    if let Some((uid, _)) = user {
+        check("setuid", unsafe { libc::setuid(uid) })?;
+        if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            return Err("setuid: was able to regain root privileges".into());
+        }
+    }
+    Ok(())
+}
+
+/// Are we running as root?
+pub fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Turn a -1 return value from libc into an error.
+fn check(call: &str, ret: libc::c_int) -> Result<()> {
+    if ret == -1 {
+        Err(format!("{}: {}", call, io::Error::last_os_error()).into())
+    } else {
+        Ok(())
+    }
+}
+
+/// Find the uid and primary gid for a user name or numeric uid.
+fn lookup_user(name: &str) -> Result<(libc::uid_t, libc::gid_t)> {
+    let cname = CString::new(name)?;
+    let mut pwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut buf = vec![0; LOOKUP_BUF_SIZE];
+    let mut result = ptr::null_mut();
+    let ret = unsafe {
+        match name.parse::<libc::uid_t>() {
+            Ok(uid) => libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result),
+            Err(_) => libc::getpwnam_r(
+                cname.as_ptr(),
+                &mut pwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            ),
+        }
+    };
+    if result.is_null() {
+        if ret != 0 {
+            return Err(format!("user {}: {}", name, io::Error::from_raw_os_error(ret)).into());
+        }
+        return Err(format!("unknown user: {}", name).into());
+    }
+    Ok((pwd.pw_uid, pwd.pw_gid))
+}
+
+/// Find the gid for a group name or numeric gid.
+fn lookup_group(name: &str) -> Result<libc::gid_t> {
+    if let Ok(gid) = name.parse() {
+        return Ok(gid);
+    }
+    let cname = CString::new(name)?;
+    let mut grp: libc::group = unsafe { mem::zeroed() };
+    let mut buf = vec![0; LOOKUP_BUF_SIZE];
+    let mut result = ptr::null_mut();
+    let ret = unsafe {
+        libc::getgrnam_r(
+            cname.as_ptr(),
+            &mut grp,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if result.is_null() {
+        if ret != 0 {
+            return Err(format!("group {}: {}", name, io::Error::from_raw_os_error(ret)).into());
+        }
+        return Err(format!("unknown group: {}", name).into());
+    }
+    Ok(grp.gr_gid)
+}
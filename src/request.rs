@@ -3,8 +3,8 @@
 //! Text, binary files as downloads, Gophermap files as menus, or
 //! executable files as dynamic content.
 
-use crate::Result;
-use std::fs;
+use crate::{cache::Cache, log::Logger, stats::Stats, Config, Result};
+use std::{fs, net::SocketAddr, sync::Arc};
 
 /// This struct represents a single gopher request.
 #[derive(Debug, Clone)]
@@ -19,17 +19,42 @@ pub struct Request {
     pub host: String,
     /// Port of the currently running server.
     pub port: u16,
+    /// Address of the client, if known.
+    pub peer: Option<SocketAddr>,
+    /// Settings in effect when this request was received.
+    pub config: Arc<Config>,
+    /// Where to log status messages about this request.
+    pub log: Logger,
+    /// Counters shared with the rest of the server.
+    pub stats: Arc<Stats>,
+    /// Rendered menus shared with the rest of the server.
+    pub cache: Arc<Cache>,
 }
 
 impl Request {
     /// Try to create a new request state object.
     pub fn from(host: &str, port: u16, root: &str) -> Result<Request> {
+        let mut config = Config::default();
+        config.host = host.into();
+        config.port = port;
+        config.root = root.into();
+        Request::with_config(Arc::new(config), Logger::default())
+    }
+
+    /// Try to create a new request state object using a server config
+    /// and logger.
+    pub fn with_config(config: Arc<Config>, log: Logger) -> Result<Request> {
         Ok(Request {
-            host: host.into(),
-            port,
-            root: fs::canonicalize(root)?.to_string_lossy().into(),
+            host: config.host.clone(),
+            port: config.port,
+            root: fs::canonicalize(&config.root)?.to_string_lossy().into(),
             selector: String::new(),
             query: String::new(),
+            peer: None,
+            config,
+            log,
+            stats: Arc::new(Stats::default()),
+            cache: Arc::new(Cache::default()),
         })
     }
 
@@ -44,7 +69,11 @@ impl Request {
 
     /// Path to the target file relative to the server root.
     pub fn relative_file_path(&self) -> String {
-        self.file_path().replace(&self.root, "")
+        let path = self.file_path();
+        match path.strip_prefix(self.root.trim_end_matches('/')) {
+            Some(rel) => rel.into(),
+            None => path,
+        }
     }
 
     /// Set selector + query based on what the client sent.
@@ -1,22 +1,67 @@
 //! A simple multi-threaded Gopher server.
 
-use crate::{color, gopher, Request, Result};
+#[cfg(feature = "async")]
+use crate::event::EventLoop;
+use crate::{
+    access::{self, AccessLog},
+    cache::{Cache, Deps},
+    color,
+    date::Date,
+    feed, gemtext, gopher,
+    limit::Limiter,
+    log::Logger,
+    markdown, metrics, privileges,
+    stats::Stats,
+    template::{self, Vars},
+    watch::{self, Event, Watcher},
+    Config, Request, Result,
+};
+use signal_hook::{
+    consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR1},
+    flag,
+};
 use std::{
     cmp::Ordering,
+    env, error, fmt,
     fs::{self, DirEntry},
     io::{self, prelude::*, BufReader, Read, Write},
     net::{SocketAddr, TcpListener, TcpStream},
-    os::unix::fs::PermissionsExt,
+    ops::Range,
+    os::unix::{
+        fs::PermissionsExt,
+        io::{AsRawFd, FromRawFd, IntoRawFd, RawFd},
+    },
     path::Path,
-    process::Command,
+    process::{self, Command},
     str,
-    sync::atomic::{AtomicBool, Ordering as AtomicOrdering},
+    sync::{
+        atomic::{AtomicBool, Ordering as AtomicOrdering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 use threadpool::ThreadPool;
+use unicode_width::UnicodeWidthStr;
+
+/// How often the accept loop wakes up to check for shutdown, in case
+/// a signal arrives just before we start waiting for a client.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Give up on a client that hasn't sent or taken anything for this
+/// long, so quiet clients can't hold on to workers forever.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// First file descriptor passed by systemd socket activation.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// How often to check whether in-flight requests have finished
+/// during a graceful shutdown.
+const DRAIN_INTERVAL: Duration = Duration::from_millis(50);
 
-/// phd tries to be light on resources, so we only allow a low number
-/// of simultaneous connections.
-const MAX_WORKERS: usize = 10;
+/// Most bytes to ask sendfile(2) for at once.
+#[cfg(target_os = "linux")]
+const SENDFILE_CHUNK: usize = 1 << 30;
 
 /// how many bytes of a file to read when trying to guess binary vs text?
 const MAX_PEEK_SIZE: usize = 1024;
@@ -24,72 +69,649 @@ const MAX_PEEK_SIZE: usize = 1024;
 /// Files not displayed in directory listings.
 const IGNORED_FILES: [&str; 3] = ["header.gph", "footer.gph", ".reverse"];
 
-/// Whether to print info!() messages to stdout.
-/// Defaults to true.
-static SHOW_INFO: AtomicBool = AtomicBool::new(true);
+/// How deep `{{include}}`s can go, so files can't include each other
+/// forever.
+const MAX_INCLUDE_DEPTH: usize = 8;
 
-/// Hide info! messages.
-fn hide_info() {
-    SHOW_INFO.swap(false, AtomicOrdering::Relaxed);
+/// What to do with new connections when the queue is full.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Overflow {
+    /// Accept them and reply with a "server busy" error.
+    Reject,
+    /// Stop accepting until there's room. Clients wait in the
+    /// kernel's listen backlog.
+    Block,
 }
 
-/// Print status message to the server's stdout.
-macro_rules! info {
-    ($e:expr) => {
-        if SHOW_INFO.load(AtomicOrdering::Relaxed) {
-            println!("{}", $e);
+impl Overflow {
+    /// Parse a policy name: `reject` or `block`.
+    pub fn from(name: &str) -> Option<Overflow> {
+        match name {
+            "reject" => Some(Overflow::Reject),
+            "block" => Some(Overflow::Block),
+            _ => None,
         }
+    }
+}
+
+/// How connections are handled.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Backend {
+    /// Each connection gets a worker thread from start to finish.
+    Threads,
+    /// One thread reads requests and writes responses for every
+    /// connection, and workers only build responses. Needs phd to be
+    /// built with the `async` feature.
+    Async,
+}
+
+impl Backend {
+    /// Parse a backend name: `threads` or `async`.
+    pub fn from(name: &str) -> Option<Backend> {
+        match name {
+            "threads" => Some(Backend::Threads),
+            "async" => Some(Backend::Async),
+            _ => None,
+        }
+    }
+}
+
+/// Starts a Gopher server using the given settings.
+///
+/// Runs until SIGTERM or SIGINT is received, at which point we stop
+/// accepting new connections and wait up to the configured grace
+/// period for in-flight requests to finish. A second signal exits
+/// immediately. SIGHUP reloads the config for new connections.
+///
+/// If phd was started by systemd with socket activation, the sockets
+/// it passed in are used instead of binding our own.
+pub fn start(config: Config) -> Result<()> {
+    let listeners = match systemd_listeners()? {
+        Some(listeners) => listeners,
+        None => vec![TcpListener::bind(config.bind_addr())?],
     };
-    ($fmt:expr, $($args:expr),*) => {
-        info!(format!($fmt, $($args),*));
-    };
-    ($fmt:expr, $($args:expr,)*) => {
-        info!(format!($fmt, $($args,)*));
+    for listener in &listeners {
+        listener.set_nonblocking(true)?;
+    }
+    let full_root_path = fs::canonicalize(&config.root)?
+        .to_string_lossy()
+        .to_string();
+    let metrics_listener = match &config.metrics {
+        Some(addr) => Some(TcpListener::bind(addr)?),
+        None => None,
     };
+    let log = open_logger(&config, None)?;
+    let access = open_access_log(&config, None)?;
+    let config = secure(config, &log)?;
+    let stats = Arc::new(Stats::default());
+    if let Some(listener) = metrics_listener {
+        info!(
+            log,
+            "{}» Serving metrics {}on {}http://{}/metrics{}",
+            color::Yellow,
+            color::Reset,
+            color::Yellow,
+            listener.local_addr()?,
+            color::Reset
+        );
+        let (stats, log) = (Arc::clone(&stats), log.clone());
+        thread::spawn(move || metrics::serve(listener, stats, log));
+    }
+
+    let signals = Signals::default();
+    for &sig in &[SIGTERM, SIGINT] {
+        flag::register_conditional_shutdown(sig, 1, Arc::clone(&signals.shutdown))?;
+        flag::register(sig, Arc::clone(&signals.shutdown))?;
+    }
+    flag::register(SIGHUP, Arc::clone(&signals.reload))?;
+    flag::register(SIGUSR1, Arc::clone(&signals.reopen))?;
+
+    for listener in &listeners {
+        info!(
+            log,
+            "{}» Listening {}on {}{}{} at {}{}{}",
+            color::Yellow,
+            color::Reset,
+            color::Yellow,
+            listener.local_addr()?,
+            color::Reset,
+            color::Blue,
+            full_root_path,
+            color::Reset
+        );
+    }
+    serve(&listeners, config, log, access, stats, &signals)
+}
+
+/// Flags set by signal handlers, for the accept loop to act on.
+#[derive(Debug, Default)]
+struct Signals {
+    /// Stop accepting and drain: SIGTERM or SIGINT.
+    shutdown: Arc<AtomicBool>,
+    /// Reload the config: SIGHUP.
+    reload: Arc<AtomicBool>,
+    /// Reopen the logs: SIGUSR1.
+    reopen: Arc<AtomicBool>,
 }
 
-/// Starts a Gopher server at the specified host, port, and root directory.
-pub fn start(bind: SocketAddr, host: &str, port: u16, root: &str) -> Result<()> {
-    let listener = TcpListener::bind(&bind)?;
-    let full_root_path = fs::canonicalize(&root)?.to_string_lossy().to_string();
-    let pool = ThreadPool::new(MAX_WORKERS);
+/// Accept connections on `listeners` and hand them to workers until
+/// we're told to shut down, then wait for in-flight requests.
+fn serve(
+    listeners: &[TcpListener],
+    config: Config,
+    mut log: Logger,
+    mut access: Option<Arc<AccessLog>>,
+    stats: Arc<Stats>,
+    signals: &Signals,
+) -> Result<()> {
+    let mut pool = ThreadPool::new(config.workers);
+    stats.set_workers(config.workers);
+    let limiter = Arc::new(Limiter::default());
+    let cache = Arc::new(Cache::default());
+    let mut watching = watch(&config, &cache, &log);
+    #[cfg(feature = "async")]
+    let events = match config.backend {
+        Backend::Async => Some(EventLoop::spawn(pool.clone(), log.clone())?),
+        Backend::Threads => None,
+    };
+    let mut config = Arc::new(config);
+
+    let mut accepted = 0;
+    let mut saturated = false;
+    while !signals.shutdown.load(AtomicOrdering::Relaxed) {
+        if signals.reload.swap(false, AtomicOrdering::Relaxed) {
+            let old = Arc::clone(&config);
+            config = reload_config(&config, &log);
+            pool.set_num_threads(config.workers);
+            stats.set_workers(config.workers);
+            cache.clear();
+            rewatch(&mut watching, &old, &config, &cache, &log);
+            match open_logger(&config, Some(&log)) {
+                Ok(new) => log = new,
+                Err(e) => error!(log, "{}» Log: {}{}", color::Red, e, color::Reset),
+            }
+            match open_access_log(&config, access.as_deref()) {
+                Ok(new) => access = new,
+                Err(e) => error!(log, "{}» Access log: {}{}", color::Red, e, color::Reset),
+            }
+        }
+        if signals.reopen.swap(false, AtomicOrdering::Relaxed) {
+            debug!(log, "{}» Reopening logs{}", color::Yellow, color::Reset);
+            if let Err(e) = log.reopen() {
+                error!(log, "{}» Log: {}{}", color::Red, e, color::Reset);
+            }
+            if let Some(Err(e)) = access.as_ref().map(|access| access.reopen()) {
+                error!(log, "{}» Access log: {}{}", color::Red, e, color::Reset);
+            }
+        }
+        let full = config.queue > 0 && pool.queued_count() >= config.queue;
+        if full != saturated {
+            saturated = full;
+            if full {
+                warn!(
+                    log,
+                    "{}» Saturated: {}all {} workers busy and {} connection(s) queued",
+                    color::Red,
+                    color::Reset,
+                    pool.max_count(),
+                    pool.queued_count()
+                );
+            } else {
+                info!(
+                    log,
+                    "{}» No longer saturated{}",
+                    color::Yellow,
+                    color::Reset
+                );
+            }
+        }
+        if full && config.overflow == Overflow::Block {
+            // Leave new clients in the kernel's backlog until a worker
+            // frees up.
+            thread::sleep(DRAIN_INTERVAL);
+            continue;
+        }
+        for listener in wait_for_clients(listeners, POLL_INTERVAL)? {
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    // Out of file descriptors, or the client gave up.
+                    // Either way, keep serving everyone else, but
+                    // don't spin if it keeps happening.
+                    error!(log, "{}» Accept: {}{}", color::Red, e, color::Reset);
+                    thread::sleep(DRAIN_INTERVAL);
+                    continue;
+                }
+            };
+            let peer = match prepare(&stream) {
+                Ok(peer) => peer,
+                Err(e) => {
+                    error!(log, "{}» Accept: {}{}", color::Red, e, color::Reset);
+                    continue;
+                }
+            };
+            info!(
+                log,
+                "{}┌ Connection{} from {}{}",
+                color::Green,
+                color::Reset,
+                color::Magenta,
+                peer
+            );
+            if config.queue > 0 && pool.queued_count() >= config.queue {
+                warn!(
+                    log,
+                    "{}└ Server busy{}, turning away {}",
+                    color::Red,
+                    color::Reset,
+                    peer.ip().to_canonical()
+                );
+                refuse(stream, "Server busy");
+                continue;
+            }
+            let permit = if config.rate_limit > 0 || config.conn_limit > 0 {
+                match limiter.admit(peer.ip(), &config) {
+                    Ok(permit) => Some(permit),
+                    Err(why) => {
+                        warn!(
+                            log,
+                            "{}└ Slow down: {}{} from {}",
+                            color::Red,
+                            why,
+                            color::Reset,
+                            peer.ip().to_canonical()
+                        );
+                        refuse(stream, &format!("Slow down: {}", why));
+                        continue;
+                    }
+                }
+            } else {
+                None
+            };
+            let mut req = match Request::with_config(Arc::clone(&config), log.clone()) {
+                Ok(req) => req,
+                Err(e) => {
+                    error!(log, "{}└ {}{}", color::Red, e, color::Reset);
+                    continue;
+                }
+            };
+            req.stats = Arc::clone(&stats);
+            req.cache = Arc::clone(&cache);
+            let access = access.clone();
+            accepted += 1;
+            #[cfg(feature = "async")]
+            {
+                if let Some(events) = &events {
+                    if let Err(e) = events.add(stream, req, access, permit) {
+                        error!(log, "{}└ {}{}", color::Red, e, color::Reset);
+                    }
+                    continue;
+                }
+            }
+            pool.execute(move || {
+                let log = req.log.clone();
+                if let Err(e) = accept(stream, req, access) {
+                    error!(log, "{}└ {}{}", color::Red, e, color::Reset);
+                }
+                drop(permit);
+            });
+        }
+    }
 
+    #[cfg(feature = "async")]
+    let open = events.as_ref().map_or(0, |events| events.open());
+    #[cfg(not(feature = "async"))]
+    let open = 0;
     info!(
-        "{}» Listening {}on {}{}{} at {}{}{}",
+        log,
+        "{}» Shutting down, {}waiting up to {}s for {} request(s)",
         color::Yellow,
         color::Reset,
+        config.grace.as_secs(),
+        pending(&pool).max(open)
+    );
+    let deadline = Instant::now() + config.grace;
+    #[cfg(feature = "async")]
+    let dropped = events.map_or(0, |events| events.drain(deadline));
+    #[cfg(not(feature = "async"))]
+    let dropped = 0;
+    let dropped = dropped.max(drain(&pool, deadline));
+    info!(
+        log,
+        "{}» Stopped. {}Accepted {} connection(s), dropped {}.",
+        color::Yellow,
+        color::Reset,
+        accepted,
+        dropped
+    );
+    Ok(())
+}
+
+/// Drop root privileges and chroot, as configured. Once chrooted, the
+/// site root is `/`.
+fn secure(mut config: Config, log: &Logger) -> Result<Config> {
+    privileges::drop_privileges(&config)?;
+    if config.chroot {
+        debug!(log, "» Chrooted into {}", config.root);
+        config.root = "/".into();
+    }
+    if privileges::is_root() {
+        warn!(
+            log,
+            "{}» Warning: running as root.{} Use --user to drop privileges.",
+            color::Red,
+            color::Reset
+        );
+    }
+    Ok(config)
+}
+
+/// Start watching the site for changes so cached menus are dropped
+/// right away, if caching is on. Watching stops when the handle we
+/// return is dropped.
+fn watch(config: &Config, cache: &Arc<Cache>, log: &Logger) -> Option<watch::Handle> {
+    if !config.cache || !config.watch {
+        return None;
+    }
+    let root = match fs::canonicalize(&config.root) {
+        Ok(root) => root,
+        Err(e) => {
+            error!(log, "{}» Watch: {}{}", color::Red, e, color::Reset);
+            return None;
+        }
+    };
+    let mut watcher = Watcher::new(root, config.watch_interval);
+    let (cache, events) = (Arc::clone(cache), log.clone());
+    watcher.subscribe(move |event| match event {
+        Event::Changed(path) => {
+            trace!(events, "» Changed: {}", path.display());
+            cache.invalidate(path);
+        }
+        Event::Rescan => cache.clear(),
+    });
+    match watcher.spawn(log.clone()) {
+        Ok(handle) => Some(handle),
+        Err(e) => {
+            error!(log, "{}» Watch: {}{}", color::Red, e, color::Reset);
+            None
+        }
+    }
+}
+
+/// After a reload, start watching again if the site moved or the watch
+/// settings changed, or if we weren't able to watch before.
+fn rewatch(
+    watching: &mut Option<watch::Handle>,
+    old: &Config,
+    new: &Config,
+    cache: &Arc<Cache>,
+    log: &Logger,
+) {
+    let changed = new.root != old.root
+        || new.cache != old.cache
+        || new.watch != old.watch
+        || new.watch_interval != old.watch_interval;
+    if watching.is_none() || changed {
+        // Stop watching the old root before starting on the new one.
+        drop(watching.take());
+        *watching = watch(new, cache, log);
+    }
+}
+
+/// Re-read the config file. Requests already in progress keep using
+/// the old settings. If the new config is bad, keep the old one.
+fn reload_config(config: &Arc<Config>, log: &Logger) -> Arc<Config> {
+    let mut new = match config.reload() {
+        Ok(new) => new,
+        Err(e) => {
+            error!(log, "{}» Reload failed: {}{}", color::Red, e, color::Reset);
+            return Arc::clone(config);
+        }
+    };
+    if new.bind_addr() != config.bind_addr()
+        || new.user != config.user
+        || new.group != config.group
+        || new.chroot != config.chroot
+        || new.metrics != config.metrics
+        || new.backend != config.backend
+    {
+        warn!(
+            log,
+            "{}» Restart phd to change bind, user, group, chroot, metrics, or backend.{}",
+            color::Red,
+            color::Reset
+        );
+    }
+    if config.chroot {
+        new.chroot = true;
+        new.root = config.root.clone();
+    }
+    info!(
+        log,
+        "{}» Reloaded {}config from {}{}{}",
         color::Yellow,
-        bind,
         color::Reset,
         color::Blue,
-        full_root_path,
+        new.path().unwrap_or("command line"),
         color::Reset
     );
-    for stream in listener.incoming() {
-        let stream = stream?;
-        info!(
-            "{}┌ Connection{} from {}{}",
-            color::Green,
-            color::Reset,
-            color::Magenta,
-            stream.peer_addr()?
+    Arc::new(new)
+}
+
+/// Tell a client to come back later, e.g. because it's over its
+/// limits. This runs on the accept loop, so it never waits for the
+/// client: the reply is sent if there's room in the socket buffer, and
+/// the connection is closed either way.
+fn refuse(stream: TcpStream, msg: &str) {
+    if stream.set_nonblocking(true).is_ok() {
+        let reply = format!("3{}. Try again later.\t/\tnone\t70\r\n", msg);
+        let _ = (&stream).write(reply.as_bytes());
+        // Closing with the request still unread would reset the
+        // connection, which can lose the reply.
+        let _ = (&stream).read(&mut [0; 1024]);
+    }
+}
+
+/// Get a newly accepted client ready for a worker, returning its
+/// address. Reads and writes time out, so a client that goes quiet
+/// can't keep the worker forever.
+fn prepare(stream: &TcpStream) -> io::Result<SocketAddr> {
+    // Accepted sockets inherit O_NONBLOCK on some platforms.
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.peer_addr()
+}
+
+/// Block until at least one listener has a connection waiting, a
+/// signal arrives, or `timeout` passes. Returns the listeners with
+/// clients waiting, if any.
+fn wait_for_clients(listeners: &[TcpListener], timeout: Duration) -> Result<Vec<&TcpListener>> {
+    let mut fds = listeners
+        .iter()
+        .map(|listener| libc::pollfd {
+            fd: listener.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect::<Vec<_>>();
+    let ms = timeout.as_millis() as libc::c_int;
+    if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, ms) } == -1 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::Interrupted {
+            return Ok(vec![]);
+        } else {
+            return Err(err.into());
+        }
+    }
+    Ok(listeners
+        .iter()
+        .zip(fds)
+        .filter(|(_, fd)| fd.revents & libc::POLLIN != 0)
+        .map(|(listener, _)| listener)
+        .collect())
+}
+
+/// Listening sockets passed to us by systemd, if we were started via
+/// socket activation. See sd_listen_fds(3).
+fn systemd_listeners() -> Result<Option<Vec<TcpListener>>> {
+    let fds = match listen_fds(
+        env::var("LISTEN_PID").ok().as_deref(),
+        env::var("LISTEN_FDS").ok().as_deref(),
+        process::id(),
+    ) {
+        Some(fds) => fds,
+        None => return Ok(None),
+    };
+
+    // Don't pass these on to any scripts we run.
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    Ok(Some(
+        fds.map(|fd| unsafe { TcpListener::from_raw_fd(fd) })
+            .collect(),
+    ))
+}
+
+/// The fds systemd passed to process `me`, given the values of
+/// `LISTEN_PID` and `LISTEN_FDS`. Like sd_listen_fds(3), we ignore
+/// them if they're meant for another process, or don't make sense.
+fn listen_fds(pid: Option<&str>, fds: Option<&str>, me: u32) -> Option<Range<RawFd>> {
+    if pid?.parse::<u32>().ok()? != me {
+        return None;
+    }
+    let fds = fds?.parse::<RawFd>().ok()?;
+    if fds < 1 {
+        return None;
+    }
+    Some(SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + fds)
+}
+
+/// Serve a single request read from stdin, writing the response to
+/// stdout. Used when phd is run from inetd, or from systemd with
+/// `Accept=yes`. Errors are logged to the `--log` file, if there is
+/// one, as well as returned, so don't print them: stderr may be the
+/// client too.
+pub fn serve_stdio(config: Config) -> Result<()> {
+    // stdout is the client, and inetd often hands us the socket as
+    // stderr too, so only log to a file.
+    let log = match config.log_file {
+        Some(_) => open_logger(&config, None)?,
+        None => Logger::silent(),
+    };
+    let result = stdio_access_log(&config, &log).and_then(|access| {
+        let config = secure(config, &log)?;
+        let req = Request::with_config(Arc::new(config), log.clone())?;
+
+        // inetd hands us the client's socket as stdin.
+        let peer = socket_peer(io::stdin().as_raw_fd());
+        let stdin = io::stdin();
+        let stdout = io::stdout();
+        respond(stdin.lock(), stdout.lock(), req, peer, access.as_deref())
+    });
+    if let Err(e) = &result {
+        error!(log, "{}» {}{}", color::Red, e, color::Reset);
+    }
+    result
+}
+
+/// Open the access log for `serve_stdio`. stdout is the client, so
+/// `access-log = -` is ignored rather than mixed into the response.
+fn stdio_access_log(config: &Config, log: &Logger) -> Result<Option<Arc<AccessLog>>> {
+    if config.access_log.as_deref() == Some("-") {
+        error!(
+            log,
+            "{}» Access log can't go to stdout with --inetd, ignoring it{}",
+            color::Red,
+            color::Reset
         );
-        let req = Request::from(host, port, root)?;
-        pool.execute(move || {
-            if let Err(e) = accept(stream, req) {
-                info!("{}└ {}{}", color::Red, e, color::Reset);
-            }
-        });
+        return Ok(None);
     }
-    Ok(())
+    open_access_log(config, None)
+}
+
+/// Address of whoever's on the other end of `fd`, if it's a connected
+/// socket. `fd` is left open.
+fn socket_peer(fd: RawFd) -> Option<SocketAddr> {
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+    let peer = stream.peer_addr().ok();
+    stream.into_raw_fd();
+    peer
+}
+
+/// Number of requests running or waiting to run.
+fn pending(pool: &ThreadPool) -> usize {
+    pool.active_count() + pool.queued_count()
+}
+
+/// Wait until `deadline` for the pool to finish its work. Returns the
+/// number of requests that were still pending when we gave up.
+fn drain(pool: &ThreadPool, deadline: Instant) -> usize {
+    while pending(pool) > 0 && Instant::now() < deadline {
+        thread::sleep(DRAIN_INTERVAL);
+    }
+    pending(pool)
 }
 
 /// Reads from the client and responds.
-fn accept(mut stream: TcpStream, mut req: Request) -> Result<()> {
-    let reader = BufReader::new(&stream);
-    let mut lines = reader.lines();
+fn accept(stream: TcpStream, req: Request, access: Option<Arc<AccessLog>>) -> Result<()> {
+    let peer = stream.peer_addr().ok();
+    respond(
+        BufReader::new(&stream),
+        &stream,
+        req,
+        peer,
+        access.as_deref(),
+    )
+}
+
+/// Read a request line from `input`, write the response to `output`,
+/// and record it in the access log.
+pub(crate) fn respond<R, W>(
+    input: R,
+    output: W,
+    req: Request,
+    peer: Option<SocketAddr>,
+    access: Option<&AccessLog>,
+) -> Result<()>
+where
+    R: BufRead,
+    W: Output,
+{
+    let (served, result) = build_response(input, output, req, peer);
+    if let (Some(served), Some(access)) = (served, access) {
+        served.log(access, served.bytes);
+    }
+    result
+}
+
+/// Like `respond`, but for when `output` is sent to the client later:
+/// the caller writes the access log entry once it's done, using what
+/// we return.
+pub(crate) fn build_response<R, W>(
+    input: R,
+    output: W,
+    mut req: Request,
+    peer: Option<SocketAddr>,
+) -> (Option<Served>, Result<()>)
+where
+    R: BufRead,
+    W: Output,
+{
+    let time = Date::now();
+    let start = Instant::now();
+    let stats = Arc::clone(&req.stats);
+    stats.begin();
+    let mut served = None;
+    let mut result = Ok(());
+    let mut lines = input.lines();
     if let Some(Ok(line)) = lines.next() {
         info!(
+            req.log,
             "{}│{} Client sent:\t{}{:?}{}",
             color::Green,
             color::Reset,
@@ -98,42 +720,331 @@ fn accept(mut stream: TcpStream, mut req: Request) -> Result<()> {
             color::Reset
         );
         req.parse_request(&line);
-        write_response(&mut stream, req)?;
+        req.peer = peer;
+        let (selector, query) = (req.selector.clone(), req.query.clone());
+        let req_log = req.log.clone();
+        let mut out = Counter::new(output);
+        let reply = write_reply(&mut out, req).and_then(|reply| {
+            out.flush()?;
+            Ok(reply)
+        });
+        let kind = match &reply {
+            Ok(reply) => reply.as_str(),
+            Err(_) => "ERROR",
+        };
+        stats.record(&selector, peer.map(|p| p.ip()), kind, out.bytes);
+        served = Some(Served {
+            time,
+            start,
+            peer,
+            selector,
+            query,
+            kind,
+            bytes: out.bytes,
+            log: req_log,
+        });
+        result = reply.map(|_| ());
+    }
+    stats.end();
+    (served, result)
+}
+
+/// A response that's been built, waiting to go in the access log.
+pub(crate) struct Served {
+    time: Date,
+    start: Instant,
+    peer: Option<SocketAddr>,
+    selector: String,
+    query: String,
+    kind: &'static str,
+    /// Size of the response.
+    pub(crate) bytes: u64,
+    log: Logger,
+}
+
+impl Served {
+    /// Write an entry to `access`, saying `bytes` reached the client
+    /// and timing the request up until now.
+    pub(crate) fn log(&self, access: &AccessLog, bytes: u64) {
+        let entry = access::Entry {
+            time: self.time,
+            peer: self.peer,
+            selector: &self.selector,
+            query: &self.query,
+            kind: self.kind,
+            bytes,
+            duration: self.start.elapsed(),
+        };
+        if let Err(e) = access.log(&entry) {
+            error!(
+                self.log,
+                "{}» Access log: {}{}",
+                color::Red,
+                e,
+                color::Reset
+            );
+        }
+    }
+}
+
+/// Set up status logging as configured.
+///
+/// If `old` logs to the same file, it's reopened through the directory
+/// `old` already has open, so this works after chrooting too.
+fn open_logger(config: &Config, old: Option<&Logger>) -> Result<Logger> {
+    let path = match &config.log_file {
+        Some(path) => path,
+        None => return Ok(Logger::new(config.log_level)),
+    };
+    match old
+        .and_then(Logger::log_file)
+        .filter(|file| file.path() == path)
+    {
+        Some(file) => Logger::to_log_file(config.log_level, file.clone()),
+        None => Logger::to_file(config.log_level, path),
+    }
+}
+
+/// Open the access log, if one is configured. Like `open_logger`, the
+/// file `old` was using is reopened through its directory.
+fn open_access_log(config: &Config, old: Option<&AccessLog>) -> Result<Option<Arc<AccessLog>>> {
+    let path = match &config.access_log {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let file = old
+        .and_then(AccessLog::log_file)
+        .filter(|file| file.path() == path);
+    Ok(Some(Arc::new(match file {
+        Some(file) => AccessLog::to_log_file(file.clone(), config.access_format)?,
+        None => AccessLog::open(path, config.access_format)?,
+    })))
+}
+
+/// Render the response to a request, exactly as a client would get
+/// it. `selector` may include a query after a tab or `?`; a non-empty
+/// `query` replaces it. `peer` is the client address to check access
+/// rules against.
+pub fn render(
+    config: &Config,
+    selector: &str,
+    query: &str,
+    peer: Option<SocketAddr>,
+) -> Result<Vec<u8>> {
+    let mut req = Request::with_config(Arc::new(config.clone()), Logger::silent())?;
+    req.parse_request(selector);
+    if !query.is_empty() {
+        req.query = query.into();
+    }
+    req.peer = peer;
+    let mut out = Counter::new(vec![]);
+    write_reply(&mut out, req)?;
+    Ok(out.inner)
+}
+
+/// The kind of response sent to a client.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Reply {
+    Dir,
+    File,
+    Map,
+    Feed,
+    NotFound,
+    Denied,
+    Stats,
+    /// Something went wrong, and the client got an error page.
+    Error,
+}
+
+impl Reply {
+    fn as_str(self) -> &'static str {
+        match self {
+            Reply::Dir => "DIR",
+            Reply::File => "FILE",
+            Reply::Map => "MAP",
+            Reply::Feed => "FEED",
+            Reply::NotFound => "NOTFOUND",
+            Reply::Denied => "DENIED",
+            Reply::Stats => "STATS",
+            Reply::Error => "ERROR",
+        }
+    }
+}
+
+/// Wraps a writer, counting the bytes that pass through it.
+struct Counter<W> {
+    inner: W,
+    bytes: u64,
+}
+
+impl<W> Counter<W> {
+    fn new(inner: W) -> Counter<W> {
+        Counter { inner, bytes: 0 }
+    }
+}
+
+impl<W: Write> Write for Counter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.bytes += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Output> Output for Counter<W> {
+    fn send_file(&mut self, file: fs::File) -> io::Result<u64> {
+        let n = self.inner.send_file(file)?;
+        self.bytes += n;
+        Ok(n)
+    }
+}
+
+/// Somewhere to write a response. Files are sent with `send_file`
+/// rather than copied through `write`, so an output can stream them
+/// however suits it best.
+pub(crate) trait Output: Write {
+    /// Send the whole of `file`, returning the number of bytes sent.
+    fn send_file(&mut self, mut file: fs::File) -> io::Result<u64> {
+        io::copy(&mut file, self)
+    }
+}
+
+impl<W: Output + ?Sized> Output for &mut W {
+    fn send_file(&mut self, file: fs::File) -> io::Result<u64> {
+        (**self).send_file(file)
+    }
+}
+
+impl Output for &TcpStream {
+    fn send_file(&mut self, file: fs::File) -> io::Result<u64> {
+        copy_file(self.as_raw_fd(), self, file)
+    }
+}
+
+impl Output for io::StdoutLock<'_> {
+    fn send_file(&mut self, file: fs::File) -> io::Result<u64> {
+        copy_file(self.as_raw_fd(), self, file)
     }
-    Ok(())
 }
 
-/// Render a response to a String.
-pub fn render(host: &str, port: u16, root: &str, selector: &str) -> Result<String> {
-    hide_info();
-    let mut req = Request::from(host, port, root)?;
-    req.parse_request(&selector);
-    let mut out = vec![];
-    write_response(&mut out, req)?;
-    Ok(String::from_utf8_lossy(&out).into())
+impl Output for Vec<u8> {}
+
+/// Copy all of `file` to `out`, whose file descriptor is `fd`. On
+/// Linux this uses sendfile(2), so the kernel moves the data without
+/// a trip through our buffers. Elsewhere, or if `fd` doesn't support
+/// it, we fall back to `io::copy`.
+fn copy_file<W: Write>(fd: RawFd, out: &mut W, mut file: fs::File) -> io::Result<u64> {
+    // Anything already written has to go out first.
+    out.flush()?;
+    #[cfg(target_os = "linux")]
+    {
+        let mut sent = 0;
+        loop {
+            match sendfile(fd, &file, SENDFILE_CHUNK) {
+                Ok(0) => return Ok(sent),
+                Ok(n) => sent += n as u64,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(ref e) if sent == 0 && sendfile_unsupported(e) => break,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = fd;
+    io::copy(&mut file, out)
+}
+
+/// Send up to `count` bytes from the current position of `file` to
+/// `fd` with sendfile(2), returning how many were sent. 0 means the
+/// whole file has been sent.
+#[cfg(target_os = "linux")]
+pub(crate) fn sendfile(fd: RawFd, file: &fs::File, count: usize) -> io::Result<usize> {
+    let n = unsafe { libc::sendfile(fd, file.as_raw_fd(), std::ptr::null_mut(), count) };
+    if n < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(n as usize)
+    }
+}
+
+/// Did sendfile fail because it can't be used with these files, rather
+/// than because something went wrong?
+#[cfg(target_os = "linux")]
+pub(crate) fn sendfile_unsupported(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(libc::EINVAL) | Some(libc::ENOSYS))
+}
+
+/// Write the response to `req`. If that fails before anything's been
+/// sent, the client gets an error page instead, and the error is
+/// logged rather than returned.
+fn write_reply<W: Output>(out: &mut Counter<W>, req: Request) -> Result<Reply> {
+    let failed = req.clone();
+    match write_response(out, req) {
+        Err(e) if out.bytes == 0 => {
+            if write_failure(out, &failed, e.as_ref()).is_err() {
+                return Err(e);
+            }
+            error!(failed.log, "{}└ {}{}", color::Red, e, color::Reset);
+            Ok(Reply::Error)
+        }
+        reply => reply,
+    }
 }
 
 /// Writes a response to a client based on a Request.
-fn write_response<W>(w: &mut W, mut req: Request) -> Result<()>
+fn write_response<W>(w: &mut W, mut req: Request) -> Result<Reply>
 where
-    W: Write,
+    W: Output,
 {
+    if !req
+        .config
+        .acl
+        .allows(&req.selector, req.peer.map(|p| p.ip()))
+    {
+        return write_denied(w, req);
+    }
+    if req.config.stats
+        && req.selector.trim_matches('/') == req.config.stats_selector.trim_matches('/')
+    {
+        return write_stats(w, req);
+    }
+
     let path = req.file_path();
 
     // check for dir.gph if we're looking for dir
     let mut gph_file = path.clone();
     gph_file.push_str(".gph");
+    trace!(
+        req.log,
+        "{}│{} Looking for:\t{}",
+        color::Green,
+        color::Reset,
+        path
+    );
     if fs_exists(&gph_file) {
         req.selector = req.selector.trim_end_matches('/').into();
         req.selector.push_str(".gph");
-        return write_gophermap(w, req);
+        return write_page(w, req);
     } else {
         // check for index.gph if we're looking for dir
         let mut index = path.clone();
         index.push_str("/index.gph");
         if fs_exists(&index) {
             req.selector.push_str("/index.gph");
-            return write_gophermap(w, req);
+            return write_page(w, req);
+        }
+    }
+
+    if req.config.feeds && !fs_exists(&path) {
+        let suffix = format!("/{}", feed::FEED_NAME);
+        if let Some(dir) = path.strip_suffix(&suffix) {
+            if fs_exists(&format!("{}/.reverse", dir)) {
+                return write_feed(w, req);
+            }
         }
     }
 
@@ -143,18 +1054,20 @@ where
     };
 
     if path.ends_with(".gph") {
-        write_gophermap(w, req)
+        write_page(w, req)
+    } else if meta.is_file() && is_document(&path, &req.config) && req.query != "raw" {
+        write_document(w, req)
     } else if meta.is_file() {
         write_file(w, req)
     } else if meta.is_dir() {
         write_dir(w, req)
     } else {
-        Ok(())
+        write_not_found(w, req)
     }
 }
 
 /// Send a directory listing (menu) to the client based on a Request.
-fn write_dir<W>(w: &mut W, req: Request) -> Result<()>
+fn write_dir<W>(w: &mut W, req: Request) -> Result<Reply>
 where
     W: Write,
 {
@@ -162,30 +1075,77 @@ where
     if !fs_exists(&path) {
         return write_not_found(w, req);
     }
+    let (header, mut deps) = find_decoration(&path, "header.gph", &req);
+    let (footer, more) = find_decoration(&path, "footer.gph", &req);
+    deps.extend(more);
+    deps.push(path.clone());
+    let decorations = header.iter().chain(footer.iter());
 
-    let mut header = path.clone();
-    header.push_str("/header.gph");
-    if fs_exists(&header) {
-        let mut sel = req.selector.clone();
-        sel.push_str("/header.gph");
-        write_gophermap(
-            w,
-            Request {
-                selector: sel,
-                ..req.clone()
-            },
-        )?;
+    // A header or footer script may use the query.
+    let key = if decorations.clone().any(|path| is_executable(path)) {
+        format!("{}\t{}", path, req.query)
+    } else {
+        path.clone()
+    };
+    let cacheable = req.config.cache && decorations.clone().all(|path| is_cacheable(path, &req));
+    let cached = if cacheable { req.cache.get(&key) } else { None };
+    match cached {
+        Some(body) => {
+            debug!(
+                req.log,
+                "{}│{} Cached:\t{}",
+                color::Green,
+                color::Reset,
+                key
+            );
+            w.write_all(&body)?;
+        }
+        None => {
+            let deps = Deps::of(&deps);
+            let (body, fixed) = dir_menu(&req, &path, header.as_deref(), footer.as_deref())?;
+            if cacheable && fixed {
+                req.cache.insert(&key, &body, deps, req.config.cache_size);
+            }
+            w.write_all(&body)?;
+        }
+    }
+
+    info!(
+        req.log,
+        "{}│{} Server reply:\t{}DIR {}{}{}",
+        color::Green,
+        color::Reset,
+        color::Yellow,
+        color::Bold,
+        req.relative_file_path(),
+        color::Reset,
+    );
+    Ok(Reply::Dir)
+}
+
+/// Render a directory listing, including its header and footer.
+/// Also returns whether they can be cached, like `write_decoration`.
+fn dir_menu(
+    req: &Request,
+    path: &str,
+    header: Option<&str>,
+    footer: Option<&str>,
+) -> Result<(Vec<u8>, bool)> {
+    let mut w = vec![];
+    let mut fixed = true;
+    if let Some(header) = header {
+        fixed &= write_decoration(&mut w, req, "Header", header)?;
     }
 
     let rel_path = req.relative_file_path();
 
     // show directory entries
     let reverse = format!("{}/.reverse", path);
-    let paths = sort_paths(&path, fs_exists(&reverse))?;
+    let paths = sort_paths(path, fs_exists(&reverse))?;
     for entry in paths {
         let file_name = entry.file_name();
         let f = file_name.to_string_lossy().to_string();
-        if f.chars().nth(0) == Some('.') || IGNORED_FILES.contains(&f.as_ref()) {
+        if !is_listed(&f, &req.config) {
             continue;
         }
         let path = format!(
@@ -196,7 +1156,7 @@ where
         write!(
             w,
             "{}{}\t{}\t{}\t{}\r\n",
-            file_type(&entry).to_char(),
+            file_type(&entry, &req.config).to_char(),
             &file_name.to_string_lossy(),
             &path,
             &req.host,
@@ -204,22 +1164,157 @@ where
         )?;
     }
 
-    let footer = format!("{}/footer.gph", path.trim_end_matches('/'));
-    if fs_exists(&footer) {
-        let sel = format!("{}/footer.gph", req.selector);
-        write_gophermap(
-            w,
-            Request {
-                selector: sel,
-                ..req.clone()
-            },
-        )?;
+    if let Some(footer) = footer {
+        fixed &= write_decoration(&mut w, req, "Footer", footer)?;
     }
 
     write!(w, ".\r\n");
+    Ok((w, fixed))
+}
+
+/// Find the `name` file (`header.gph` or `footer.gph`) for the
+/// directory at `dir`, along with every path that decided it. With
+/// `inherit`, a directory without one uses its nearest parent's,
+/// stopping at the root or at a directory with a `.noinherit` file.
+fn find_decoration(dir: &str, name: &str, req: &Request) -> (Option<String>, Vec<String>) {
+    let root = req.root.trim_end_matches('/');
+    let mut dir = dir.trim_end_matches('/').to_string();
+    let mut checked = vec![];
+    loop {
+        let path = format!("{}/{}", dir, name);
+        checked.push(path.clone());
+        if fs_exists(&path) {
+            return (Some(path), checked);
+        }
+        if !req.config.inherit {
+            break;
+        }
+        let stop = format!("{}/.noinherit", dir);
+        checked.push(stop.clone());
+        if fs_exists(&stop) || dir.len() <= root.len() {
+            break;
+        }
+        match dir.rfind('/') {
+            Some(i) if i >= root.len() => dir.truncate(i),
+            _ => break,
+        }
+    }
+    (None, checked)
+}
+
+/// Render the header or footer at `path`. Returns whether it can be
+/// cached, like `gophermap`.
+fn write_decoration<W>(w: &mut W, req: &Request, kind: &str, path: &str) -> Result<bool>
+where
+    W: Write,
+{
+    trace!(
+        req.log,
+        "{}│{} {}:\t{}",
+        color::Green,
+        color::Reset,
+        kind,
+        path
+    );
+    let req = Request {
+        selector: selector_for(path, req),
+        ..req.clone()
+    };
+    let executable = is_executable(path);
+    if executable && !req.config.acl.allows_script(req.peer.map(|p| p.ip())) {
+        write_denied(w, req)?;
+        return Ok(false);
+    }
+    gophermap(w, &req, path, executable)
+}
+
+/// The selector for a file under the site root.
+fn selector_for(path: &str, req: &Request) -> String {
+    let root = req.root.trim_end_matches('/');
+    path.strip_prefix(root).unwrap_or(path).to_string()
+}
 
+/// Send a gophermap that's a page of its own, with the header and
+/// footer of its directory around it if `map-headers` is on.
+fn write_page<W>(w: &mut W, req: Request) -> Result<Reply>
+where
+    W: Write,
+{
+    let file = req.file_path();
+    let path = Path::new(&file);
+    let name = path.file_name().unwrap_or_default();
+    // Scripts the client can't run just get the error.
+    let denied = is_executable(&file) && !req.config.acl.allows_script(req.peer.map(|p| p.ip()));
+    if !req.config.map_headers || denied || name == "header.gph" || name == "footer.gph" {
+        return write_gophermap(w, req);
+    }
+    let dir = path.parent().unwrap_or(path).to_string_lossy().to_string();
+    let (header, _) = find_decoration(&dir, "header.gph", &req);
+    let (footer, _) = find_decoration(&dir, "footer.gph", &req);
+    if let Some(header) = &header {
+        write_decoration(w, &req, "Header", header)?;
+    }
+    let reply = write_gophermap(w, req.clone())?;
+    if let Some(footer) = &footer {
+        write_decoration(w, &req, "Footer", footer)?;
+    }
+    Ok(reply)
+}
+
+/// Send the server statistics page as a menu of info lines.
+fn write_stats<W>(w: &mut W, req: Request) -> Result<Reply>
+where
+    W: Write,
+{
+    for line in req.stats.report() {
+        write!(w, "i{}\t(null)\t{}\t{}\r\n", line, req.host, req.port)?;
+    }
+    write!(w, ".\r\n")?;
     info!(
-        "{}│{} Server reply:\t{}DIR {}{}{}",
+        req.log,
+        "{}│{} Server reply:\t{}STATS{}",
+        color::Green,
+        color::Reset,
+        color::Yellow,
+        color::Reset,
+    );
+    Ok(Reply::Stats)
+}
+
+/// Send an Atom feed of the posts in a `.reverse` directory, newest
+/// first.
+fn write_feed<W>(w: &mut W, req: Request) -> Result<Reply>
+where
+    W: Write,
+{
+    let path = req.file_path();
+    let dir = path.trim_end_matches(feed::FEED_NAME).trim_end_matches('/');
+    let selector = req.selector.trim_end_matches(feed::FEED_NAME);
+    let mut entries = vec![];
+    for entry in sort_paths(dir, true)? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !is_listed(&name, &req.config)
+            || name == feed::FEED_NAME
+            || !entry.file_type().is_ok_and(|t| t.is_file())
+        {
+            continue;
+        }
+        let sel = format!("{}/{}", selector.trim_end_matches('/'), name);
+        entries.push(feed::Entry::new(
+            &entry.path(),
+            file_type(&entry, &req.config),
+            &sel,
+        ));
+    }
+    let title = match selector.trim_matches('/').rsplit('/').next() {
+        Some(name) if !name.is_empty() => name,
+        _ => &req.host,
+    };
+    let xml = feed::atom(title, selector, &entries, &req.host, req.port);
+    w.write_all(xml.as_bytes())?;
+    info!(
+        req.log,
+        "{}│{} Server reply:\t{}FEED {}{}{}",
         color::Green,
         color::Reset,
         color::Yellow,
@@ -227,18 +1322,18 @@ where
         req.relative_file_path(),
         color::Reset,
     );
-    Ok(())
+    Ok(Reply::Feed)
 }
 
 /// Send a file to the client based on a Request.
-fn write_file<W>(w: &mut W, req: Request) -> Result<()>
+fn write_file<W>(w: &mut W, req: Request) -> Result<Reply>
 where
-    W: Write,
+    W: Output,
 {
     let path = req.file_path();
-    let mut f = fs::File::open(&path)?;
-    io::copy(&mut f, w)?;
+    w.send_file(fs::File::open(&path)?)?;
     info!(
+        req.log,
         "{}│{} Server reply:\t{}FILE {}{}{}",
         color::Green,
         color::Reset,
@@ -247,27 +1342,272 @@ where
         req.relative_file_path(),
         color::Reset,
     );
-    Ok(())
+    Ok(Reply::File)
+}
+
+/// Send a gophermap (menu) to the client based on a Request.
+fn write_gophermap<W>(w: &mut W, req: Request) -> Result<Reply>
+where
+    W: Write,
+{
+    let path = req.file_path();
+    let executable = is_executable(&path);
+    if executable && !req.config.acl.allows_script(req.peer.map(|p| p.ip())) {
+        return write_denied(w, req);
+    }
+    gophermap(w, &req, &path, executable)?;
+
+    info!(
+        req.log,
+        "{}│{} Server reply:\t{}MAP {}{}{}",
+        color::Green,
+        color::Reset,
+        color::Yellow,
+        color::Bold,
+        req.relative_file_path(),
+        color::Reset,
+    );
+    Ok(Reply::Map)
+}
+
+/// Write the gophermap at `path`, from the cache if we can. Returns
+/// whether it can be cached: templates and most scripts can change
+/// from one request to the next, so they aren't.
+fn gophermap<W>(w: &mut W, req: &Request, path: &str, executable: bool) -> Result<bool>
+where
+    W: Write,
+{
+    // Scripts' output can depend on the query.
+    let key = if executable {
+        format!("{}\t{}", path, req.query)
+    } else {
+        path.to_string()
+    };
+    let cacheable = req.config.cache && is_cacheable(path, req);
+    if let Some(body) = cacheable.then(|| req.cache.get(&key)).flatten() {
+        debug!(
+            req.log,
+            "{}│{} Cached:\t{}",
+            color::Green,
+            color::Reset,
+            key
+        );
+        w.write_all(&body)?;
+        return Ok(true);
+    }
+    let deps = Deps::of(&[path]);
+    let (body, templated) = gophermap_menu(req, path, executable)?;
+    let cacheable = cacheable && !templated;
+    if cacheable {
+        req.cache.insert(&key, &body, deps, req.config.cache_size);
+    }
+    w.write_all(&body)?;
+    Ok(cacheable)
+}
+
+/// Render a gophermap, running it first if it's executable. Also
+/// returns whether it used templates.
+fn gophermap_menu(req: &Request, path: &str, executable: bool) -> Result<(Vec<u8>, bool)> {
+    // Run the file and use its output as content if it's executable.
+    let mut templated = false;
+    let reader = if executable {
+        debug!(
+            req.log,
+            "{}│{} Running script:\t{}",
+            color::Green,
+            color::Reset,
+            path
+        );
+        let started = Instant::now();
+        let output = shell(path, &[&req.query, &req.host, &req.port.to_string()]);
+        req.stats
+            .script(started.elapsed(), matches!(output, Ok((true, _))));
+        match output? {
+            (true, stdout) => stdout,
+            (false, stderr) => {
+                return Err(ScriptError {
+                    path: selector_for(path, req),
+                    stderr,
+                }
+                .into())
+            }
+        }
+    } else {
+        let text = fs::read_to_string(path)?;
+        if req.config.templates && template::is_template(&text) {
+            templated = true;
+            let env = GphEnv {
+                req,
+                selector: selector_for(path, req),
+                depth: 0,
+            };
+            template::render(&text, &env)?
+        } else {
+            text
+        }
+    };
+
+    // A `#nowrap` comment anywhere keeps ASCII art intact.
+    let wrap = req.config.wrap && !reader.lines().any(|line| line.trim_end() == "#nowrap");
+
+    let mut w = vec![];
+    for line in reader.lines() {
+        let line = line.trim_end_matches('\r');
+        if wrap && is_info_line(line) && line.width() > req.config.width {
+            for line in wrap_info_line(line, req.config.width) {
+                write!(w, "i{}\t(null)\t{}\t{}\r\n", line, req.host, req.port)?;
+            }
+        } else {
+            write!(w, "{}", gph_line_to_gopher(line, req))?;
+        }
+    }
+    Ok((w, templated))
+}
+
+/// Data for the templates in a gophermap.
+struct GphEnv<'a> {
+    req: &'a Request,
+    /// Selector of the file being rendered. Paths are relative to it.
+    selector: String,
+    /// How many includes deep we are.
+    depth: usize,
+}
+
+impl GphEnv<'_> {
+    /// Find the selector for `path`, if the client is allowed to see
+    /// it.
+    fn resolve(&self, path: &str) -> Result<String> {
+        let selector = resolve_link(&self.selector, path);
+        if !self
+            .req
+            .config
+            .acl
+            .allows(&selector, self.req.peer.map(|p| p.ip()))
+        {
+            return Err(format!("{}: access denied", path).into());
+        }
+        Ok(selector)
+    }
+
+    /// Where `selector` is on disk.
+    fn file_path(&self, selector: &str) -> String {
+        format!("{}{}", self.req.root.trim_end_matches('/'), selector)
+    }
+}
+
+impl template::Env for GphEnv<'_> {
+    fn var(&self, name: &str) -> Option<String> {
+        match name {
+            "host" => Some(self.req.host.clone()),
+            "port" => Some(self.req.port.to_string()),
+            "selector" => Some(page_selector(&self.req.selector)),
+            "query" => Some(self.req.query.clone()),
+            "date" => Some(Date::now().to_string()),
+            _ => None,
+        }
+    }
+
+    fn include(&self, path: &str) -> Result<String> {
+        if self.depth >= MAX_INCLUDE_DEPTH {
+            return Err(format!("{}: includes nested too deeply", path).into());
+        }
+        let selector = self.resolve(path)?;
+        let text = fs::read_to_string(self.file_path(&selector))
+            .map_err(|e| format!("{}: {}", path, e))?;
+        let env = GphEnv {
+            req: self.req,
+            selector,
+            depth: self.depth + 1,
+        };
+        template::render(&text, &env)
+    }
+
+    fn entries(&self, path: &str) -> Result<Vec<Vars>> {
+        let selector = self.resolve(path)?;
+        let dir = self.file_path(&selector);
+        let reverse = fs_exists(&format!("{}/.reverse", dir));
+        let mut entries = vec![];
+        for entry in sort_paths(&dir, reverse).map_err(|e| format!("{}: {}", path, e))? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !is_listed(&name, &self.req.config) {
+                continue;
+            }
+            let sel = format!("{}/{}", selector.trim_end_matches('/'), name);
+            let typ = file_type(&entry, &self.req.config);
+            let post = feed::Entry::new(&entry.path(), typ, &sel);
+            let size = entry.metadata().map(|meta| meta.len()).unwrap_or(0);
+            let mut vars = Vars::new();
+            vars.insert("name".into(), name);
+            vars.insert("title".into(), post.title);
+            vars.insert("selector".into(), sel);
+            vars.insert("type".into(), typ.to_char().to_string());
+            vars.insert("date".into(), post.date.to_string());
+            vars.insert("size".into(), size.to_string());
+            entries.push(vars);
+        }
+        Ok(entries)
+    }
+}
+
+/// The page a gophermap is shown for: its directory, for headers,
+/// footers, and index files.
+fn page_selector(selector: &str) -> String {
+    for name in &["index.gph", "header.gph", "footer.gph"] {
+        if let Some(dir) = selector.strip_suffix(name) {
+            if dir.ends_with('/') {
+                let dir = dir.trim_end_matches('/');
+                return if dir.is_empty() {
+                    "/".into()
+                } else {
+                    dir.into()
+                };
+            }
+        }
+    }
+    selector.into()
 }
 
-/// Send a gophermap (menu) to the client based on a Request.
-fn write_gophermap<W>(w: &mut W, req: Request) -> Result<()>
+/// Will this line of a gophermap become an info line? Comments, links,
+/// and lines with tabs won't.
+fn is_info_line(line: &str) -> bool {
+    let link = line.starts_with('[') && line.ends_with(']') && line.contains('|');
+    !(link || line.starts_with('#') || line.contains('\t'))
+}
+
+/// Split a long info line into lines at most `width` columns wide,
+/// keeping its indentation.
+fn wrap_info_line(line: &str, width: usize) -> Vec<String> {
+    let text = line.trim_start();
+    let indent = &line[..line.len() - text.len()];
+    markdown::wrap(text, width, indent, indent)
+}
+
+/// Send a Markdown or gemtext file to the client as a menu. `?raw`
+/// gets the file itself.
+fn write_document<W>(w: &mut W, req: Request) -> Result<Reply>
 where
     W: Write,
 {
     let path = req.file_path();
-
-    // Run the file and use its output as content if it's executable.
-    let reader = if is_executable(&path) {
-        shell(&path, &[&req.query, &req.host, &req.port.to_string()])?
+    let text = fs::read_to_string(&path)?;
+    let lines = if is_gemtext(&path) {
+        gemtext::convert(&text, req.config.width)
     } else {
-        fs::read_to_string(&path)?
+        markdown::convert(&text, req.config.width)
     };
-
-    for line in reader.lines() {
-        write!(w, "{}", gph_line_to_gopher(line, &req))?;
+    for line in lines {
+        match line {
+            markdown::Line::Text(text) => {
+                write!(w, "i{}\t(null)\t{}\t{}\r\n", text, req.host, req.port)?
+            }
+            markdown::Line::Link { text, target } => {
+                write!(w, "{}", document_link(&text, &target, &req))?
+            }
+        }
     }
+    write!(w, ".\r\n")?;
     info!(
+        req.log,
         "{}│{} Server reply:\t{}MAP {}{}{}",
         color::Green,
         color::Reset,
@@ -276,7 +1616,103 @@ where
         req.relative_file_path(),
         color::Reset,
     );
-    Ok(())
+    Ok(Reply::Map)
+}
+
+/// Turn a link in a Markdown or gemtext file into a menu item. Paths are relative
+/// to the file, like on the web, and get the type of what they point
+/// to. `gopher://` links point to other servers, and anything else
+/// with a scheme, like `gemini://` or `https://`, is a `URL:` link.
+fn document_link(text: &str, target: &str, req: &Request) -> String {
+    if let Some(url) = target.strip_prefix("gopher://") {
+        let (addr, path) = url.split_at(url.find('/').unwrap_or(url.len()));
+        let (host, port) = match addr.rfind(':') {
+            Some(i) if !addr.ends_with(']') => (&addr[..i], &addr[i + 1..]),
+            _ => (addr, "70"),
+        };
+        // IPv6 addresses are bracketed in URLs, but not in menus.
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let path = path.trim_start_matches('/');
+        let mut chars = path.chars();
+        let (typ, selector) = match chars.next() {
+            Some(t) => (t, chars.as_str()),
+            None => ('1', ""),
+        };
+        return format!("{}{}\t{}\t{}\t{}\r\n", typ, text, selector, host, port);
+    }
+    let has_scheme = target.find(':').is_some_and(|i| {
+        i > 0
+            && target[..i]
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.')
+    });
+    if has_scheme {
+        return format!("h{}\tURL:{}\t{}\t{}\r\n", text, target, req.host, req.port);
+    }
+
+    let target = target.split('#').next().unwrap_or("");
+    if target.is_empty() {
+        // A link to somewhere on the same page.
+        return format!("i{}\t(null)\t{}\t{}\r\n", text, req.host, req.port);
+    }
+    let (path, query) = match target.find('?') {
+        Some(i) => target.split_at(i),
+        None => (target, ""),
+    };
+    let selector = resolve_link(&req.selector, path);
+    let typ = if query == "?raw" {
+        gopher::Type::Text
+    } else {
+        let file = format!("{}{}", req.root.trim_end_matches('/'), selector);
+        guess_type(Path::new(&file), &req.config)
+    };
+    format!(
+        "{}{}\t{}{}\t{}\t{}\r\n",
+        typ.to_char(),
+        text,
+        selector,
+        query,
+        req.host,
+        req.port
+    )
+}
+
+/// Find the selector for a path linked from the page at `selector`.
+/// `..` can't climb above the root.
+fn resolve_link(selector: &str, path: &str) -> String {
+    let mut parts: Vec<&str> = if path.starts_with('/') {
+        vec![]
+    } else {
+        let mut dir: Vec<&str> = selector.split('/').filter(|p| !p.is_empty()).collect();
+        dir.pop();
+        dir
+    };
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    format!("/{}", parts.join("/"))
+}
+
+/// Is this a Markdown file?
+fn is_markdown(name: &str) -> bool {
+    let name = name.to_lowercase();
+    name.ends_with(".md") || name.ends_with(".markdown")
+}
+
+/// Is this a gemtext file?
+fn is_gemtext(name: &str) -> bool {
+    name.to_lowercase().ends_with(".gmi")
+}
+
+/// Should this file be converted to a menu?
+fn is_document(name: &str, config: &Config) -> bool {
+    (config.markdown && is_markdown(name)) || (config.gemtext && is_gemtext(name))
 }
 
 /// Given a single line from a .gph file, convert it into a
@@ -333,41 +1769,144 @@ fn gph_line_to_gopher(line: &str, req: &Request) -> String {
     line
 }
 
-fn write_not_found<W>(w: &mut W, req: Request) -> Result<()>
+fn write_not_found<W>(w: &mut W, req: Request) -> Result<Reply>
 where
     W: Write,
 {
-    let line = format!("3Not Found: {}\t/\tnone\t70\r\n", req.selector);
     info!(
+        req.log,
         "{}│ Not found: {}{}{}",
         color::Red,
         color::Cyan,
         req.relative_file_path(),
         color::Reset,
     );
-    write!(w, "{}", line)?;
+    write_error_page(w, &req, 404, &format!("Not Found: {}", req.selector))?;
+    Ok(Reply::NotFound)
+}
+
+/// Tell a client they aren't allowed to see this selector.
+fn write_denied<W>(w: &mut W, req: Request) -> Result<Reply>
+where
+    W: Write,
+{
+    warn!(
+        req.log,
+        "{}│ Access denied: {}{}{} for {}",
+        color::Red,
+        color::Cyan,
+        req.relative_file_path(),
+        color::Reset,
+        req.peer.map_or("unknown client".into(), |p| p
+            .ip()
+            .to_canonical()
+            .to_string()),
+    );
+    write_error_page(w, &req, 403, &format!("Access denied: {}", req.selector))?;
+    Ok(Reply::Denied)
+}
+
+/// A gophermap script exited with an error.
+#[derive(Debug)]
+struct ScriptError {
+    /// Selector of the script.
+    path: String,
+    /// What it printed to stderr.
+    stderr: String,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: script failed", self.path)?;
+        let stderr = self.stderr.trim();
+        if !stderr.is_empty() {
+            write!(f, ": {}", stderr)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for ScriptError {}
+
+/// Tell a client its request failed because of `err`: a script that
+/// failed, a file we aren't allowed to read, or anything else.
+fn write_failure<W>(w: &mut W, req: &Request, err: &(dyn error::Error + 'static)) -> Result<()>
+where
+    W: Write,
+{
+    let (code, message) = if err.downcast_ref::<ScriptError>().is_some() {
+        (500, "Script failed")
+    } else {
+        match err.downcast_ref::<io::Error>().map(|e| e.kind()) {
+            Some(io::ErrorKind::PermissionDenied) => (403, "Permission denied"),
+            Some(io::ErrorKind::NotFound) => (404, "Not Found"),
+            _ => (500, "Server error"),
+        }
+    };
+    write_error_page(w, req, code, &format!("{}: {}", message, req.selector))
+}
+
+/// Send the site's error page for `code`, like `/.errors/404.gph`, or
+/// an error line with `message` if there isn't one.
+fn write_error_page<W>(w: &mut W, req: &Request, code: u16, message: &str) -> Result<()>
+where
+    W: Write,
+{
+    match error_page(req, code) {
+        Some(body) => w.write_all(&body)?,
+        None => write!(w, "3{}\t/\tnone\t70\r\n", message)?,
+    }
     Ok(())
 }
 
+/// Render the site's error page for `code`, if it has one that works.
+/// Templates in it see the selector that was asked for.
+fn error_page(req: &Request, code: u16) -> Option<Vec<u8>> {
+    let path = format!(
+        "{}{}/{}.gph",
+        req.root.trim_end_matches('/'),
+        req.config.error_pages.trim_end_matches('/'),
+        code
+    );
+    if !fs_exists(&path) {
+        return None;
+    }
+    let executable = is_executable(&path);
+    if executable && !req.config.acl.allows_script(req.peer.map(|p| p.ip())) {
+        return None;
+    }
+    match gophermap_menu(req, &path, executable) {
+        Ok((body, _)) => Some(body),
+        Err(e) => {
+            error!(
+                req.log,
+                "{}│ Error page {}: {}{}",
+                color::Red,
+                code,
+                e,
+                color::Reset
+            );
+            None
+        }
+    }
+}
+
 /// Determine the gopher type for a DirEntry on disk.
-fn file_type(dir: &fs::DirEntry) -> gopher::Type {
+fn file_type(dir: &fs::DirEntry, config: &Config) -> gopher::Type {
     let metadata = match dir.metadata() {
         Err(_) => return gopher::Type::Error,
         Ok(md) => md,
     };
 
     if metadata.is_file() {
-        if let Ok(file) = fs::File::open(&dir.path()) {
-            let mut buffer: Vec<u8> = vec![];
-            let _ = file.take(MAX_PEEK_SIZE as u64).read_to_end(&mut buffer);
-            if content_inspector::inspect(&buffer).is_binary() {
-                gopher::Type::Binary
-            } else {
-                gopher::Type::Text
-            }
-        } else {
-            gopher::Type::Error
+        let name = dir.file_name().to_string_lossy().to_string();
+        if is_document(&name, config) {
+            return gopher::Type::Menu;
         }
+        if let Some(t) = config.type_for(&name) {
+            return t;
+        }
+        sniff_type(&dir.path())
     } else if metadata.is_dir() {
         gopher::Type::Menu
     } else {
@@ -375,11 +1914,70 @@ fn file_type(dir: &fs::DirEntry) -> gopher::Type {
     }
 }
 
+/// Like `file_type`, but for a path that may not exist, e.g. a link in
+/// a document. Missing files are guessed from their name.
+fn guess_type(path: &Path, config: &Config) -> gopher::Type {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let gph = format!("{}.gph", path.to_string_lossy());
+    if path.is_dir() || name.ends_with(".gph") || fs_exists(&gph) {
+        return gopher::Type::Menu;
+    }
+    if is_document(&name, config) {
+        return gopher::Type::Menu;
+    }
+    if let Some(t) = config.type_for(&name) {
+        return t;
+    }
+    if path.exists() {
+        sniff_type(path)
+    } else {
+        gopher::Type::Text
+    }
+}
+
+/// Text or binary? Peek at the start of a file to find out.
+fn sniff_type(path: &Path) -> gopher::Type {
+    if let Ok(file) = fs::File::open(path) {
+        let mut buffer: Vec<u8> = vec![];
+        let _ = file.take(MAX_PEEK_SIZE as u64).read_to_end(&mut buffer);
+        if content_inspector::inspect(&buffer).is_binary() {
+            gopher::Type::Binary
+        } else {
+            gopher::Type::Text
+        }
+    } else {
+        gopher::Type::Error
+    }
+}
+
+/// Should a file with this name show up in directory listings?
+fn is_listed(name: &str, config: &Config) -> bool {
+    !name.starts_with('.') && !IGNORED_FILES.contains(&name) && !config.is_ignored(name)
+}
+
 /// Does the file exist? Y'know.
 fn fs_exists(path: &str) -> bool {
     Path::new(path).exists()
 }
 
+/// Can the gophermap at `path` be cached for this request? Static
+/// gophermaps, or ones that don't exist, can, unless they turn out to
+/// be templates once they're read. Scripts can only if they match
+/// `cache-scripts` and the client may run them.
+fn is_cacheable(path: &str, req: &Request) -> bool {
+    if !is_executable(path) {
+        return true;
+    }
+    let name = Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    req.config.is_cacheable_script(&name) && req.config.acl.allows_script(req.peer.map(|p| p.ip()))
+}
+
 /// Is the file at the given path executable?
 fn is_executable(path: &str) -> bool {
     if let Ok(meta) = fs::metadata(path) {
@@ -389,13 +1987,14 @@ fn is_executable(path: &str) -> bool {
     }
 }
 
-/// Run a script and return its output.
-fn shell(path: &str, args: &[&str]) -> Result<String> {
+/// Run a script and return whether it succeeded, along with its
+/// output (stdout on success, stderr on failure).
+fn shell(path: &str, args: &[&str]) -> Result<(bool, String)> {
     let output = Command::new(path).args(args).output()?;
     if output.status.success() {
-        Ok(str::from_utf8(&output.stdout)?.to_string())
+        Ok((true, str::from_utf8(&output.stdout)?.to_string()))
     } else {
-        Ok(str::from_utf8(&output.stderr)?.to_string())
+        Ok((false, str::from_utf8(&output.stderr)?.to_string()))
     }
 }
 
@@ -433,6 +2032,8 @@ fn sort_paths(dir_path: &str, reverse: bool) -> Result<Vec<DirEntry>> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{config::DEFAULT_STATS_SELECTOR, log::Level, testing::TempDir};
+    use std::path::PathBuf;
 
     macro_rules! str_path {
         ($e:expr) => {
@@ -522,4 +2123,753 @@ mod tests {
             "empty type\t\tlocalhost\t7070\r\n",
         );
     }
+
+    /// Render `selector` as text, the way a client would get it.
+    fn get(config: &Config, selector: &str) -> String {
+        String::from_utf8(render(config, selector, "", None).unwrap()).unwrap()
+    }
+
+    /// Like `get`, but keeping `req`'s cache between requests.
+    fn get_cached(req: &Request, selector: &str) -> String {
+        let mut req = req.clone();
+        req.parse_request(selector);
+        let mut out = vec![];
+        write_response(&mut out, req).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_stats_selector() {
+        let root = TempDir::new("stats");
+        let mut config = root.config();
+        config.stats_selector = "/_stats".into();
+
+        assert_eq!(
+            get(&config, "/_stats"),
+            "3Not Found: /_stats\t/\tnone\t70\r\n"
+        );
+
+        config.stats = true;
+        let out = get(&config, "/_stats/");
+        assert!(out.starts_with("i~ phd stats ~\t(null)\t"), "{}", out);
+        assert!(out.contains("iRequests:"));
+        assert!(out.ends_with(".\r\n"));
+        assert!(get(&config, DEFAULT_STATS_SELECTOR).starts_with("3Not Found"));
+    }
+
+    #[test]
+    fn test_cached_menus() {
+        let root = TempDir::new("cached");
+        root.write("dir/a.txt", "a");
+        root.write("dir/footer.gph", "footer one");
+        root.script("map.gph", "#!/bin/sh\necho \"hi $1\"\n");
+
+        let mut config = root.config();
+        config.cache = true;
+        config.templates = true;
+        let req = Request::with_config(Arc::new(config), Logger::silent()).unwrap();
+
+        assert!(get_cached(&req, "/dir").contains("ifooter one"));
+        // The listing, and the footer on its own.
+        assert_eq!(req.cache.len(), 2);
+        root.write("dir/b.txt", "b");
+        assert!(get_cached(&req, "/dir").contains("0b.txt"));
+        root.write("dir/footer.gph", "footer two");
+        assert!(get_cached(&req, "/dir").contains("ifooter two"));
+
+        // Scripts aren't cached unless they're listed in cache-scripts.
+        assert!(get_cached(&req, "/map.gph\tx").contains("ihi x"));
+        assert_eq!(req.cache.len(), 2);
+
+        // Neither are templates, or listings with them around.
+        root.write("said.gph", "You said {{query}}");
+        root.write("tdir/header.gph", "{{query}}");
+        for query in &["x", "y"] {
+            let said = get_cached(&req, &format!("/said.gph\t{}", query));
+            assert!(said.starts_with(&format!("iYou said {}\t", query)));
+            let listing = get_cached(&req, &format!("/tdir\t{}", query));
+            assert!(listing.starts_with(&format!("i{}\t", query)));
+        }
+        assert_eq!(req.cache.len(), 2);
+    }
+
+    #[test]
+    fn test_render() {
+        let root = TempDir::new("render");
+        root.mkdir("private");
+        let junk: Vec<u8> = (0..=255).collect();
+        root.write("junk.bin", &junk);
+        root.script("echo.gph", "#!/bin/sh\necho \"you said $1\"\n");
+
+        let mut config = root.config();
+        config.parse("allow = /private 10.0.0.0/8").unwrap();
+        assert_eq!(render(&config, "/junk.bin", "", None).unwrap(), junk);
+        let out = render(&config, "/echo", "hi there", None).unwrap();
+        assert_eq!(out, b"iyou said hi there\t(null)\t127.0.0.1\t7070\r\n");
+        let out = render(&config, "/echo?ignored", "used", None).unwrap();
+        assert!(out.starts_with(b"iyou said used\t"));
+
+        let inside = "10.1.2.3:0".parse().ok();
+        let outside = "192.0.2.1:0".parse().ok();
+        assert!(render(&config, "/private", "", inside)
+            .unwrap()
+            .ends_with(b".\r\n"));
+        assert!(render(&config, "/private", "", outside)
+            .unwrap()
+            .starts_with(b"3"));
+    }
+
+    #[test]
+    fn test_markdown() {
+        let root = TempDir::new("markdown");
+        root.write("about.txt", "About\n");
+        let md = "# Hi\n\nSee [about](../about.txt), [the web](https://example.com), \
+                  [home](gopher://[::1]/0/a), and [the source](first.md?raw).\n";
+        root.write("posts/first.md", md);
+
+        let mut config = root.config();
+        assert_eq!(get(&config, "/posts/first.md"), md);
+
+        config.markdown = true;
+        let out = get(&config, "/posts/first.md");
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[0], "iHi\t(null)\t127.0.0.1\t7070");
+        assert_eq!(lines[1], "i==\t(null)\t127.0.0.1\t7070");
+        assert!(lines.contains(&"0about\t/about.txt\t127.0.0.1\t7070"));
+        assert!(lines.contains(&"hthe web\tURL:https://example.com\t127.0.0.1\t7070"));
+        assert!(lines.contains(&"0home\t/a\t::1\t70"));
+        assert!(lines.contains(&"0the source\t/posts/first.md?raw\t127.0.0.1\t7070"));
+        assert!(out.ends_with(".\r\n"));
+
+        assert_eq!(get(&config, "/posts/first.md?raw"), md);
+        assert!(get(&config, "/posts").contains("1first.md\t/posts/first.md\t"));
+    }
+
+    #[test]
+    fn test_gemtext() {
+        let root = TempDir::new("gemtext");
+        root.mkdir("phlog");
+        let gmi = "# Capsule\n=> phlog/ Phlog\n=> gemini://example.com Gemini\n\
+                   => gopher://example.com:7070/0/a.txt A\n\
+                   => gopher://[::1]:7071/1/b B\n=> gopher://[::1] C\n";
+        root.write("index.gmi", gmi);
+
+        let mut config = root.config();
+        config.gemtext = true;
+        let out = get(&config, "/index.gmi");
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            &lines[..7],
+            &[
+                "iCapsule\t(null)\t127.0.0.1\t7070",
+                "i=======\t(null)\t127.0.0.1\t7070",
+                "1Phlog\t/phlog\t127.0.0.1\t7070",
+                "hGemini\tURL:gemini://example.com\t127.0.0.1\t7070",
+                "0A\t/a.txt\texample.com\t7070",
+                "1B\t/b\t::1\t7071",
+                "1C\t\t::1\t70",
+            ]
+        );
+        assert_eq!(get(&config, "/index.gmi?raw"), gmi);
+        assert!(get(&config, "/").contains("1index.gmi\t/index.gmi\t"));
+    }
+
+    #[test]
+    fn test_wrap() {
+        let root = TempDir::new("wrap");
+        let long = "  one two three four five six";
+        let link = "[1|a link that is far too long to fit|/]";
+        root.write(
+            "map.gph",
+            format!("{}\n{}\n日本語 日本語 日本語\n", long, link),
+        );
+        root.write("art.gph", format!("#nowrap\n{}\n", long));
+        root.write("long.txt", long);
+
+        let mut config = root.config();
+        assert!(get(&config, "/map").starts_with(&format!("i{}\t", long)));
+
+        config.parse("wrap = true\nwidth = 14").unwrap();
+        let map = get(&config, "/map");
+        let lines: Vec<&str> = map.lines().map(|l| l.split('\t').next().unwrap()).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "i  one two",
+                "i  three four",
+                "i  five six",
+                "1a link that is far too long to fit",
+                "i日本語 日本語",
+                "i日本語",
+            ]
+        );
+        assert!(get(&config, "/art").starts_with(&format!("i{}\t", long)));
+        assert_eq!(get(&config, "/long.txt"), long);
+    }
+
+    #[test]
+    fn test_feed() {
+        let root = TempDir::new("feed-server");
+        root.write("phlog/.reverse", "");
+        root.write("phlog/header.gph", "My phlog");
+        root.write("phlog/2020-01-01-new-year.txt", "New Year\n");
+        root.write("phlog/2020-02-01-feb.txt", "February\n");
+
+        let mut config = root.config();
+        assert!(get(&config, "/phlog/feed.xml").starts_with('3'));
+        config.feeds = true;
+        let xml = get(&config, "/phlog/feed.xml");
+        assert!(xml.contains("<title>phlog</title>"));
+        assert!(xml.contains("<updated>2020-02-01T00:00:00Z</updated>"));
+        let feb = xml.find("<title>February</title>").unwrap();
+        let jan = xml.find("<title>New Year</title>").unwrap();
+        assert!(feb < jan);
+        assert!(xml.contains("gopher://127.0.0.1:7070/0/phlog/2020-02-01-feb.txt"));
+        assert!(!xml.contains("header.gph"));
+
+        // Only for .reverse directories, and a real feed.xml wins.
+        assert!(get(&config, "/feed.xml").starts_with('3'));
+        root.write("phlog/feed.xml", "mine");
+        assert_eq!(get(&config, "/phlog/feed.xml"), "mine");
+        config.feeds = false;
+        fs::remove_file(root.join("phlog/feed.xml")).unwrap();
+        assert!(get(&config, "/phlog/feed.xml").starts_with('3'));
+    }
+
+    #[test]
+    fn test_templates() {
+        let root = TempDir::new("template");
+        root.write("phlog/.reverse", "");
+        root.write("phlog/2020-01-01-one.txt", "First\n");
+        root.write("phlog/2020-02-01-two.txt", "Second\n");
+        root.write("parts/nav.gph", "[1|Home|/|server|port]\n");
+        root.write("parts/loop.gph", "{{include loop.gph}}");
+        root.write("private/secret.gph", "secret\n");
+        root.write(
+            "phlog/header.gph",
+            "{{selector}} on {{host}}:{{port}}\n{{include ../parts/nav.gph}}\n\
+             {{#each .}}\n[0|{{date}} {{title}}|{{selector}}|server|port]\n{{/each}}\n",
+        );
+        root.write("loop.gph", "{{include parts/loop.gph}}");
+        root.write("secret.gph", "{{include private/secret.gph}}");
+        root.write("search.gph", "You said {{query}}");
+        root.write("results.gph", "[1|Result: {{query}}|/search|server|port]");
+
+        let mut config = root.config();
+        config.parse("deny = /private 0.0.0.0/0").unwrap();
+        config.templates = true;
+        let out = get(&config, "/phlog");
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            &lines[..4],
+            &[
+                "i/phlog on 127.0.0.1:7070\t(null)\t127.0.0.1\t7070",
+                "1Home\t/\t127.0.0.1\t7070",
+                "02020-02-01 Second\t/phlog/2020-02-01-two.txt\t127.0.0.1\t7070",
+                "02020-01-01 First\t/phlog/2020-01-01-one.txt\t127.0.0.1\t7070",
+            ]
+        );
+        assert!(render(&config, "/search", "hi", None)
+            .unwrap()
+            .starts_with(b"iYou said hi\t"));
+        // Queries can't add fields or lines to the menu.
+        assert_eq!(
+            get(&config, "/search\tx\tfake\thost\t70"),
+            "iYou said x fake host 70\t(null)\t127.0.0.1\t7070\r\n"
+        );
+        assert!(render(&config, "/search", "x\r\n1evil\t/\tevil\t70", None)
+            .unwrap()
+            .starts_with(b"iYou said x  1evil / evil 70\t(null)\t127.0.0.1\t7070\r\n"));
+        // Nor can they rewrite a link.
+        assert_eq!(
+            render(
+                &config,
+                "/results",
+                "x|/etc/passwd|evil.example|6666]",
+                None
+            )
+            .unwrap(),
+            b"1Result: x\\|/etc/passwd\\|evil.example\\|6666\t/search\t127.0.0.1\t7070\r\n"
+        );
+        assert!(render(&config, "/loop", "", None)
+            .unwrap()
+            .starts_with(b"3Server error: /loop\t"));
+        let peer = "192.0.2.1:0".parse().ok();
+        assert!(render(&config, "/secret", "", peer)
+            .unwrap()
+            .starts_with(b"3Server error: /secret\t"));
+
+        config.templates = false;
+        assert!(render(&config, "/search", "hi", None)
+            .unwrap()
+            .starts_with(b"iYou said {{query}}\t"));
+    }
+
+    #[test]
+    fn test_inherit() {
+        let root = TempDir::new("inherit");
+        root.mkdir("a/b");
+        root.mkdir("quiet/c");
+        root.write("header.gph", "banner");
+        root.write("a/footer.gph", "a footer");
+        root.write("a/page.gph", "page");
+        root.write("quiet/.noinherit", "");
+
+        let mut config = root.config();
+        assert!(get(&config, "/").starts_with("ibanner\t"));
+        assert!(!get(&config, "/a/b").contains("banner"));
+
+        config.inherit = true;
+        let b = get(&config, "/a/b");
+        assert!(b.starts_with("ibanner\t"));
+        assert!(b.contains("ia footer\t"));
+        assert!(!get(&config, "/quiet").contains("banner"));
+        assert!(!get(&config, "/quiet/c").contains("banner"));
+        assert!(get(&config, "/a/page").starts_with("ipage\t"));
+
+        config.map_headers = true;
+        let page = get(&config, "/a/page");
+        let lines: Vec<&str> = page
+            .lines()
+            .map(|l| l.split('\t').next().unwrap())
+            .collect();
+        assert_eq!(lines, vec!["ibanner", "ipage", "ia footer"]);
+        assert!(get(&config, "/a/footer.gph").starts_with("ia footer\t"));
+    }
+
+    #[test]
+    fn test_inherit_cache() {
+        let root = TempDir::new("inherit-cache");
+        root.mkdir("a/b");
+        let mut config = root.config();
+        config.cache = true;
+        config.inherit = true;
+        let req = Request::with_config(Arc::new(config), Logger::silent()).unwrap();
+
+        assert!(!get_cached(&req, "/a/b").contains("banner"));
+        assert_eq!(req.cache.len(), 1);
+        // A new header further up still shows up in the cached listing.
+        root.write("header.gph", "banner");
+        assert!(get_cached(&req, "/a/b").starts_with("ibanner\t"));
+        // And so do changes to it.
+        root.write("header.gph", "new banner");
+        assert!(get_cached(&req, "/a/b").starts_with("inew banner\t"));
+    }
+
+    #[test]
+    fn test_rewatch() {
+        let (a, b) = (TempDir::new("rewatch-a"), TempDir::new("rewatch-b"));
+        let (mut old, mut new) = (a.config(), b.config());
+        old.cache = true;
+        new.cache = true;
+        let (cache, log) = (Arc::new(Cache::default()), Logger::silent());
+        let mut watching = watch(&old, &cache, &log);
+        assert!(watching.is_some());
+
+        // After a reload moves the site, changes to the new root are
+        // noticed without anyone asking for the menu.
+        rewatch(&mut watching, &old, &new, &cache, &log);
+        thread::sleep(Duration::from_millis(200));
+        cache.insert("/", b"menu", Deps::of(&[b.path()]), 100);
+        b.write("new.txt", "");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !cache.is_empty() {
+            assert!(Instant::now() < deadline, "change to new root not seen");
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        let mut off = new.clone();
+        off.cache = false;
+        rewatch(&mut watching, &new, &off, &cache, &log);
+        assert!(watching.is_none());
+    }
+
+    #[test]
+    fn test_reopen_logs() {
+        let tmp = TempDir::new("logs");
+        tmp.mkdir("logs");
+        let mut config = tmp.config();
+        config.log_file = Some(tmp.join("logs/phd.log").to_string_lossy().into());
+        config.access_log = Some(tmp.join("logs/access.log").to_string_lossy().into());
+        let log = open_logger(&config, None).unwrap();
+        let access = open_access_log(&config, None).unwrap();
+
+        // Once chrooted, the paths in the config lead nowhere, but
+        // reloading still finds the same files.
+        fs::rename(tmp.join("logs"), tmp.join("moved")).unwrap();
+        fs::remove_file(tmp.join("moved/phd.log")).unwrap();
+        let log = open_logger(&config, Some(&log)).unwrap();
+        assert!(tmp.join("moved/phd.log").exists());
+        assert!(open_access_log(&config, access.as_deref()).is_ok());
+        assert!(open_logger(&config, None).is_err());
+        log.reopen().unwrap();
+    }
+
+    /// `serve` running in the background on a free port. It's shut
+    /// down when dropped.
+    struct TestServer {
+        addr: SocketAddr,
+        shutdown: Arc<AtomicBool>,
+        thread: Option<thread::JoinHandle<()>>,
+    }
+
+    impl TestServer {
+        fn start(config: Config, log: Logger) -> TestServer {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.set_nonblocking(true).unwrap();
+            let addr = listener.local_addr().unwrap();
+            let signals = Signals::default();
+            let shutdown = Arc::clone(&signals.shutdown);
+            let access = open_access_log(&config, None).unwrap();
+            let thread = thread::spawn(move || {
+                let stats = Arc::new(Stats::default());
+                serve(&[listener], config, log, access, stats, &signals).unwrap();
+            });
+            TestServer {
+                addr,
+                shutdown,
+                thread: Some(thread),
+            }
+        }
+
+        fn connect(&self) -> TcpStream {
+            let stream = TcpStream::connect(self.addr).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            stream
+        }
+
+        fn get(&self, selector: &str) -> String {
+            send(&mut self.connect(), selector)
+        }
+    }
+
+    impl Drop for TestServer {
+        fn drop(&mut self) {
+            self.shutdown.store(true, AtomicOrdering::Relaxed);
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    /// Send a request on `stream` and read the whole response.
+    fn send(stream: &mut TcpStream, selector: &str) -> String {
+        write!(stream, "{}\r\n", selector).unwrap();
+        let mut out = String::new();
+        stream.read_to_string(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_overflow_reject() {
+        let root = TempDir::new("reject");
+        root.write("site/a.txt", "a");
+        let mut config = root.config();
+        config.root = root.join("site").to_string_lossy().into();
+        config.grace = Duration::from_secs(1);
+        config
+            .parse("workers = 1\nqueue = 1\noverflow = reject")
+            .unwrap();
+        let log = root.join("phd.log").to_string_lossy().to_string();
+        let server = TestServer::start(config, Logger::to_file(Level::Info, &log).unwrap());
+
+        // One client ties up the only worker and another waits in the
+        // queue, so the next is turned away right away.
+        let mut busy = server.connect();
+        thread::sleep(Duration::from_millis(200));
+        let mut queued = server.connect();
+        thread::sleep(Duration::from_millis(200));
+        assert!(server
+            .get("/a.txt")
+            .starts_with("3Server busy. Try again later.\t"));
+
+        // Once the worker is free, everyone's served again.
+        assert_eq!(send(&mut busy, "/a.txt"), "a");
+        assert_eq!(send(&mut queued, "/a.txt"), "a");
+        assert_eq!(server.get("/a.txt"), "a");
+        // The accept loop notices on its next pass.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !fs::read_to_string(&log)
+            .unwrap()
+            .contains("No longer saturated")
+        {
+            assert!(Instant::now() < deadline, "still saturated");
+            thread::sleep(Duration::from_millis(50));
+        }
+        drop(server);
+        let log = fs::read_to_string(&log).unwrap();
+        assert!(log.contains("Saturated: all 1 workers busy and 1 connection(s) queued"));
+        assert!(log.contains("Server busy, turning away 127.0.0.1"));
+    }
+
+    #[test]
+    fn test_overflow_block() {
+        let root = TempDir::new("block");
+        root.write("a.txt", "a");
+        let mut config = root.config();
+        config.grace = Duration::from_secs(1);
+        config
+            .parse("workers = 1\nqueue = 1\noverflow = block")
+            .unwrap();
+        let server = TestServer::start(config, Logger::silent());
+
+        let mut busy = server.connect();
+        thread::sleep(Duration::from_millis(200));
+        let mut queued = server.connect();
+        thread::sleep(Duration::from_millis(200));
+
+        // The next client waits in the backlog rather than being
+        // turned away...
+        let mut waiting = server.connect();
+        write!(waiting, "/a.txt\r\n").unwrap();
+        waiting
+            .set_read_timeout(Some(Duration::from_millis(300)))
+            .unwrap();
+        assert!(waiting.read(&mut [0; 16]).is_err());
+
+        // ...until there's room for it.
+        assert_eq!(send(&mut busy, "/a.txt"), "a");
+        assert_eq!(send(&mut queued, "/a.txt"), "a");
+        waiting
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut out = String::new();
+        waiting.read_to_string(&mut out).unwrap();
+        assert_eq!(out, "a");
+    }
+
+    #[test]
+    fn test_prepare() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        let (stream, _) = listener.accept().unwrap();
+
+        // Workers wait for quiet clients, but not forever.
+        assert_eq!(prepare(&stream).unwrap(), client.local_addr().unwrap());
+        assert_eq!(stream.read_timeout().unwrap(), Some(CLIENT_TIMEOUT));
+        assert_eq!(stream.write_timeout().unwrap(), Some(CLIENT_TIMEOUT));
+        drop(client);
+        assert_eq!((&stream).read(&mut [0; 16]).unwrap(), 0);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_async_backend() {
+        let root = TempDir::new("async");
+        root.write("site/dir/a.txt", "a");
+        let big = vec![b'x'; 300_000];
+        root.write("site/big.txt", &big);
+        root.write("site/huge.txt", vec![b'x'; 16 << 20]);
+        let mut config = root.config();
+        config.root = root.join("site").to_string_lossy().into();
+        config.backend = Backend::Async;
+        config.access_log = Some(root.join("access.log").to_string_lossy().into());
+        config.grace = Duration::from_secs(5);
+        let server = TestServer::start(config, Logger::silent());
+
+        let dir = server.get("/");
+        assert!(dir.contains("1dir\t/dir\t"));
+        let file = server.get("/big.txt");
+        assert_eq!(file.as_bytes(), &big[..]);
+        let missing = server.get("/nope");
+        assert!(missing.starts_with("3"));
+
+        // A client that hangs up early is logged with what it got.
+        let mut quitter = server.connect();
+        write!(quitter, "/huge.txt\r\n").unwrap();
+        quitter.read_exact(&mut [0; 1024]).unwrap();
+        drop(quitter);
+        drop(server);
+
+        let log = fs::read_to_string(root.join("access.log")).unwrap();
+        let entries: Vec<(&str, u64)> = log
+            .lines()
+            .map(|line| {
+                let fields: Vec<_> = line.rsplitn(4, ' ').collect();
+                (fields[2], fields[1].parse().unwrap())
+            })
+            .collect();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0], ("DIR", dir.len() as u64));
+        assert_eq!(entries[1], ("FILE", big.len() as u64));
+        assert_eq!(entries[2], ("NOTFOUND", missing.len() as u64));
+        assert_eq!(entries[3].0, "FILE");
+        assert!(entries[3].1 >= 1024 && entries[3].1 < 16 << 20);
+    }
+
+    #[test]
+    fn test_listen_fds() {
+        assert_eq!(listen_fds(Some("1234"), Some("2"), 1234), Some(3..5));
+        assert_eq!(listen_fds(Some("1234"), Some("1"), 1234), Some(3..4));
+        // Meant for someone else, or nonsense.
+        assert_eq!(listen_fds(Some("4321"), Some("2"), 1234), None);
+        assert_eq!(listen_fds(None, Some("2"), 1234), None);
+        assert_eq!(listen_fds(Some("me"), Some("2"), 1234), None);
+        assert_eq!(listen_fds(Some("1234"), Some("0"), 1234), None);
+        assert_eq!(listen_fds(Some("1234"), Some("-1"), 1234), None);
+        assert_eq!(listen_fds(Some("1234"), Some("two"), 1234), None);
+        assert_eq!(listen_fds(Some("1234"), None, 1234), None);
+    }
+
+    #[test]
+    fn test_inetd() {
+        let root = TempDir::new("inetd");
+        root.write("hello.txt", "Hello!\n");
+        let req = Request::with_config(Arc::new(root.config()), Logger::silent()).unwrap();
+
+        // Like with inetd, the client's socket is our input and output.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        client.write_all(b"/hello.txt\r\n").unwrap();
+        let peer = socket_peer(stream.as_raw_fd());
+        assert_eq!(peer, client.local_addr().ok());
+        respond(BufReader::new(&stream), &stream, req, peer, None).unwrap();
+        drop(stream);
+        let mut out = String::new();
+        client.read_to_string(&mut out).unwrap();
+        assert_eq!(out, "Hello!\n");
+
+        // Files and pipes don't have a peer.
+        let file = fs::File::open(root.join("hello.txt")).unwrap();
+        assert_eq!(socket_peer(file.as_raw_fd()), None);
+
+        // stdout is the client, so it can't be the access log too.
+        let mut config = root.config();
+        let log = root.join("phd.log").to_string_lossy().to_string();
+        let logger = Logger::to_file(Level::Error, &log).unwrap();
+        config.access_log = Some("-".into());
+        assert!(stdio_access_log(&config, &logger).unwrap().is_none());
+        assert!(fs::read_to_string(&log)
+            .unwrap()
+            .contains("Access log can't go to stdout"));
+        config.access_log = Some(root.join("access.log").to_string_lossy().into());
+        assert!(stdio_access_log(&config, &logger).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_error_pages() {
+        let root = TempDir::new("errors");
+        root.write("private/secret.txt", "secret");
+        root.write(
+            ".errors/404.gph",
+            "No {{selector}} here.\n[1|Home|/|server|port]\n",
+        );
+        root.script("fail.gph", "#!/bin/sh\necho oops >&2\nexit 1\n");
+
+        let mut config = root.config();
+        config.parse("deny = /private 0.0.0.0/0").unwrap();
+        config.templates = true;
+        let out = get(&config, "/nope");
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            &lines[..2],
+            &[
+                "iNo /nope here.\t(null)\t127.0.0.1\t7070",
+                "1Home\t/\t127.0.0.1\t7070"
+            ]
+        );
+
+        // No 403 page, so it's the usual error line.
+        let peer = "192.0.2.1:0".parse().ok();
+        let out = render(&config, "/private/secret.txt", "", peer).unwrap();
+        assert!(out.starts_with(b"3Access denied: /private/secret.txt\t"));
+
+        // Failing scripts get the 500 page, not their stderr, which
+        // only goes in the log.
+        let log = root.join("phd.log").to_string_lossy().to_string();
+        let logger = Logger::to_file(Level::Error, &log).unwrap();
+        let req = Request::with_config(Arc::new(config.clone()), logger).unwrap();
+        let mut out = vec![];
+        respond(&b"/fail\r\n"[..], &mut out, req.clone(), None, None).unwrap();
+        assert!(out.starts_with(b"3Script failed: /fail\t"));
+        assert!(!String::from_utf8(out).unwrap().contains("oops"));
+        assert!(fs::read_to_string(&log).unwrap().contains("oops"));
+        root.write(".errors/500.gph", "Sorry, {{selector}} broke.");
+        let mut out = vec![];
+        respond(&b"/fail\r\n"[..], &mut out, req, None, None).unwrap();
+        assert!(out.starts_with(b"iSorry, /fail broke.\t"));
+
+        // Rendering gets the same pages a client would.
+        let out = render(&config, "/fail", "", None).unwrap();
+        assert!(out.starts_with(b"iSorry, /fail broke.\t"));
+        let out = render(&config, "/nope", "", None).unwrap();
+        assert!(out.starts_with(b"iNo /nope here.\t"));
+    }
+
+    /// Write `size` bytes of junk to a temp file and return its path.
+    fn temp_file(dir: &TempDir, size: usize) -> PathBuf {
+        let path = dir.join("junk");
+        let chunk: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+        let mut file = fs::File::create(&path).unwrap();
+        let mut left = size;
+        while left > 0 {
+            let n = left.min(chunk.len());
+            file.write_all(&chunk[..n]).unwrap();
+            left -= n;
+        }
+        path
+    }
+
+    /// Send `path` over a local TCP connection with `send`, returning
+    /// what the other end received and how long it took.
+    fn send_over_tcp<F>(path: &Path, send: F) -> (Vec<u8>, Duration)
+    where
+        F: FnOnce(&TcpStream, fs::File) -> io::Result<u64> + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let reader = thread::spawn(move || {
+            let mut out = vec![];
+            TcpStream::connect(addr)
+                .unwrap()
+                .read_to_end(&mut out)
+                .unwrap();
+            out
+        });
+        let (stream, _) = listener.accept().unwrap();
+        let file = fs::File::open(path).unwrap();
+        let start = Instant::now();
+        send(&stream, file).unwrap();
+        drop(stream);
+        let out = reader.join().unwrap();
+        (out, start.elapsed())
+    }
+
+    #[test]
+    fn test_send_file() {
+        let dir = TempDir::new("send-file");
+        let path = temp_file(&dir, 300_000);
+        let expected = fs::read(&path).unwrap();
+        let (out, _) = send_over_tcp(&path, |mut stream, file| {
+            write!(stream, "header\r\n")?;
+            stream.send_file(file)
+        });
+        assert_eq!(&out[..8], b"header\r\n");
+        assert_eq!(&out[8..], &expected[..]);
+
+        let mut out = vec![];
+        out.send_file(fs::File::open(&path).unwrap()).unwrap();
+        assert_eq!(out, expected);
+    }
+
+    /// Compare sendfile(2) against io::copy for a big file:
+    /// `cargo test --release bench_send_file -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_send_file() {
+        let dir = TempDir::new("bench");
+        let path = temp_file(&dir, 256 * 1024 * 1024);
+        let (out, fast) = send_over_tcp(&path, |mut stream, file| stream.send_file(file));
+        assert_eq!(out.len(), 256 * 1024 * 1024);
+        let (out, slow) = send_over_tcp(&path, |mut stream, mut file| {
+            io::copy(&mut file, &mut stream)
+        });
+        assert_eq!(out.len(), 256 * 1024 * 1024);
+        println!("send_file: {:?}, io::copy: {:?}", fast, slow);
+    }
 }
@@ -0,0 +1,305 @@
+//! Snapshot tests for a gopher site. Each `.snap` file in a directory
+//! names a request and the exact response it should get:
+//!
+//! ```text
+//! # Executable gophermaps get the query as $1.
+//! selector: /echo
+//! query: hello
+//! ---
+//! iYou said: hello<TAB>(null)<TAB>127.0.0.1<TAB>7070
+//! ```
+//!
+//! (with real tabs and a `\r\n` line ending)
+//!
+//! `phd --test DIR` renders every request with `server::render` and
+//! shows what changed. `--update` writes the current responses back
+//! into the files instead, which is also how new snapshots get their
+//! expected output.
+
+use crate::{color, server, Config, Result};
+use std::{
+    fs,
+    io::Write,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+/// Separates the request from the expected response.
+const SEPARATOR: &str = "---\n";
+
+/// Longest diffs we bother lining up. Bigger ones are compared line
+/// by line.
+const MAX_DIFF_LINES: usize = 2000;
+
+/// A request and the response it should get.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    /// Selector to request.
+    pub selector: String,
+    /// Search query to send, if any.
+    pub query: String,
+    /// Address the request should seem to come from, if any.
+    pub peer: Option<SocketAddr>,
+    /// The response we expect, or None if it hasn't been recorded.
+    pub expected: Option<Vec<u8>>,
+}
+
+impl Snapshot {
+    /// Parse the contents of a `.snap` file.
+    pub fn parse(bytes: &[u8]) -> Result<Snapshot> {
+        let (head, expected) = match find(bytes, SEPARATOR.as_bytes()) {
+            Some(i) => (&bytes[..i], Some(bytes[i + SEPARATOR.len()..].to_vec())),
+            None => (bytes, None),
+        };
+        let mut snap = Snapshot {
+            selector: String::new(),
+            query: String::new(),
+            peer: None,
+            expected,
+        };
+        let mut has_selector = false;
+        for line in String::from_utf8_lossy(head).lines() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, val) = match line.find(':') {
+                Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+                None => return Err(format!("expected `key: value`, got: {}", line).into()),
+            };
+            match key {
+                "selector" => {
+                    snap.selector = val.into();
+                    has_selector = true;
+                }
+                "query" => snap.query = val.into(),
+                "peer" => {
+                    let peer = val
+                        .parse()
+                        .ok()
+                        .or_else(|| val.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, 0)));
+                    snap.peer = Some(peer.ok_or_else(|| format!("bad peer address: {}", val))?);
+                }
+                _ => return Err(format!("unknown key: {}", key).into()),
+            }
+        }
+        if !has_selector {
+            return Err("missing `selector:`".into());
+        }
+        Ok(snap)
+    }
+
+    /// The `.snap` file for this request, expecting `response`.
+    pub fn to_bytes(&self, response: &[u8]) -> Vec<u8> {
+        let mut out = format!("selector: {}\n", self.selector);
+        if !self.query.is_empty() {
+            out.push_str(&format!("query: {}\n", self.query));
+        }
+        if let Some(peer) = self.peer {
+            out.push_str(&format!("peer: {}\n", peer.ip()));
+        }
+        out.push_str(SEPARATOR);
+        let mut out = out.into_bytes();
+        out.extend_from_slice(response);
+        out
+    }
+}
+
+/// How a run went.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Summary {
+    /// Snapshots that matched.
+    pub passed: usize,
+    /// Snapshots that didn't, or couldn't be read or rendered.
+    pub failed: usize,
+    /// Snapshots rewritten with `update`.
+    pub updated: usize,
+}
+
+/// Check every `.snap` file in `dir` against the site in `config`,
+/// reporting to `out`. With `update`, rewrite the files that don't
+/// match instead of failing.
+pub fn run<W: Write>(config: &Config, dir: &Path, update: bool, mut out: W) -> Result<Summary> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .map_err(|e| format!("{}: {}", dir.display(), e))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "snap"))
+        .collect();
+    paths.sort();
+
+    let mut summary = Summary::default();
+    for path in paths {
+        let name = path.file_stem().unwrap_or_default().to_string_lossy();
+        let result = fs::read(&path)
+            .map_err(|e| e.into())
+            .and_then(|bytes| Snapshot::parse(&bytes))
+            .and_then(|snap| {
+                let actual = server::render(config, &snap.selector, &snap.query, snap.peer)?;
+                Ok((snap, actual))
+            });
+        let (snap, actual) = match result {
+            Ok(result) => result,
+            Err(e) => {
+                summary.failed += 1;
+                writeln!(out, "{}FAIL{} {}: {}", color::Red, color::Reset, name, e)?;
+                continue;
+            }
+        };
+        if snap.expected.as_deref() == Some(&actual[..]) {
+            summary.passed += 1;
+            writeln!(out, "{}ok{}   {}", color::Green, color::Reset, name)?;
+        } else if update {
+            fs::write(&path, snap.to_bytes(&actual))?;
+            summary.updated += 1;
+            writeln!(out, "{}updated{} {}", color::Yellow, color::Reset, name)?;
+        } else {
+            summary.failed += 1;
+            match &snap.expected {
+                Some(expected) => {
+                    writeln!(out, "{}FAIL{} {}", color::Red, color::Reset, name)?;
+                    write_diff(&mut out, expected, &actual)?;
+                }
+                None => writeln!(
+                    out,
+                    "{}FAIL{} {}: no expected output. Run with --update to record it.",
+                    color::Red,
+                    color::Reset,
+                    name
+                )?,
+            }
+        }
+    }
+    writeln!(
+        out,
+        "{} passed, {} failed, {} updated",
+        summary.passed, summary.failed, summary.updated
+    )?;
+    Ok(summary)
+}
+
+/// Show the lines that differ between two responses.
+fn write_diff<W: Write>(out: &mut W, expected: &[u8], actual: &[u8]) -> Result<()> {
+    let expected = String::from_utf8_lossy(expected);
+    let actual = String::from_utf8_lossy(actual);
+    let old: Vec<&str> = expected.split_inclusive('\n').collect();
+    let new: Vec<&str> = actual.split_inclusive('\n').collect();
+    for (sign, line) in diff(&old, &new) {
+        let line = line.trim_end_matches('\n').trim_end_matches('\r');
+        match sign {
+            '-' => writeln!(out, "    {}- {:?}{}", color::Red, line, color::Reset)?,
+            '+' => writeln!(out, "    {}+ {:?}{}", color::Green, line, color::Reset)?,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Line diff: each line tagged `-` (only in `old`), `+` (only in
+/// `new`), or ` ` (in both), using the longest common subsequence.
+fn diff<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<(char, &'a str)> {
+    if old.len() > MAX_DIFF_LINES || new.len() > MAX_DIFF_LINES {
+        let mut lines = vec![];
+        for i in 0..old.len().max(new.len()) {
+            match (old.get(i), new.get(i)) {
+                (Some(a), Some(b)) if a == b => lines.push((' ', *a)),
+                (a, b) => {
+                    lines.extend(a.map(|a| ('-', *a)));
+                    lines.extend(b.map(|b| ('+', *b)));
+                }
+            }
+        }
+        return lines;
+    }
+
+    // lcs[i][j] is the LCS length of old[i..] and new[j..].
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j, mut lines) = (0, 0, vec![]);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push((' ', old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            lines.push(('-', old[i]));
+            i += 1;
+        } else {
+            lines.push(('+', new[j]));
+            j += 1;
+        }
+    }
+    lines
+}
+
+/// Index of the first `needle` in `haystack` that starts a line.
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    (0..=haystack.len().saturating_sub(needle.len()))
+        .find(|&i| haystack[i..].starts_with(needle) && (i == 0 || haystack[i - 1] == b'\n'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn test_parse() {
+        let snap =
+            Snapshot::parse(b"# echo\nselector: /echo\nquery: a b\npeer: ::1\n---\nbody\n---\n")
+                .unwrap();
+        assert_eq!(snap.selector, "/echo");
+        assert_eq!(snap.query, "a b");
+        assert_eq!(snap.peer, "[::1]:0".parse().ok());
+        assert_eq!(snap.expected.as_deref(), Some(&b"body\n---\n"[..]));
+        assert_eq!(
+            Snapshot::parse(&snap.to_bytes(b"body\n---\n")).unwrap(),
+            snap
+        );
+        assert_eq!(Snapshot::parse(b"selector: /\n").unwrap().expected, None);
+        assert!(Snapshot::parse(b"query: x\n---\n").is_err());
+        assert!(Snapshot::parse(b"selector: /\ncolour: blue\n").is_err());
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = ["a\n", "b\n", "c\n"];
+        let new = ["a\n", "c\n", "d\n"];
+        assert_eq!(
+            diff(&old, &new),
+            vec![(' ', "a\n"), ('-', "b\n"), (' ', "c\n"), ('+', "d\n")]
+        );
+    }
+
+    #[test]
+    fn test_fixture() {
+        let mut config = Config::default();
+        config.root = "tests/site".into();
+        let mut out = vec![];
+        let summary = run(&config, Path::new("tests/snapshots"), false, &mut out).unwrap();
+        let out = String::from_utf8_lossy(&out);
+        assert_eq!(summary.failed, 0, "{}", out);
+        assert!(summary.passed > 0);
+    }
+
+    #[test]
+    fn test_update() {
+        let dir = TempDir::new("snap");
+        dir.write("hello.snap", "selector: /hello.txt\n");
+        let mut config = Config::default();
+        config.root = "tests/site".into();
+
+        let summary = run(&config, dir.path(), false, vec![]).unwrap();
+        assert_eq!(summary.failed, 1);
+        let summary = run(&config, dir.path(), true, vec![]).unwrap();
+        assert_eq!(summary.updated, 1);
+        let summary = run(&config, dir.path(), false, vec![]).unwrap();
+        assert_eq!(summary.passed, 1);
+    }
+}
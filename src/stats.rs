@@ -0,0 +1,374 @@
+//! Counters for a running server, shown as a gopher menu at
+//! `/.phd/stats` when the `stats` setting is on, and exported to
+//! Prometheus by the `metrics` module.
+
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Only keep counts for this many different selectors or clients, so
+/// a crawler can't make us use unlimited memory.
+const MAX_TRACKED: usize = 1000;
+
+/// How many selectors and clients to show in the top lists.
+const TOP_N: usize = 10;
+
+/// Bucket bounds for how long scripts take to run, in seconds.
+const SCRIPT_SECONDS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Bucket bounds for response sizes, in bytes.
+const RESPONSE_BYTES: &[f64] = &[
+    256.0,
+    1024.0,
+    4096.0,
+    16_384.0,
+    65_536.0,
+    262_144.0,
+    1_048_576.0,
+    4_194_304.0,
+];
+
+/// Counts how many observed values fall under each of a set of
+/// bounds, Prometheus-style.
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    inner: Mutex<HistogramCounts>,
+}
+
+#[derive(Debug, Default, Clone)]
+struct HistogramCounts {
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    /// A histogram with buckets for each of `bounds`, plus one for
+    /// everything bigger.
+    pub fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram {
+            bounds,
+            inner: Mutex::new(HistogramCounts {
+                buckets: vec![0; bounds.len()],
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Add a value.
+    pub fn observe(&self, val: f64) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(i) = self.bounds.iter().position(|&b| val <= b) {
+            inner.buckets[i] += 1;
+        }
+        inner.sum += val;
+        inner.count += 1;
+    }
+
+    /// Each bucket's upper bound and the number of values less than or
+    /// equal to it.
+    pub fn buckets(&self) -> Vec<(f64, u64)> {
+        let inner = self.inner.lock().unwrap();
+        let mut total = 0;
+        self.bounds
+            .iter()
+            .zip(&inner.buckets)
+            .map(|(&bound, n)| {
+                total += n;
+                (bound, total)
+            })
+            .collect()
+    }
+
+    /// Sum of all values.
+    pub fn sum(&self) -> f64 {
+        self.inner.lock().unwrap().sum
+    }
+
+    /// Number of values.
+    pub fn count(&self) -> u64 {
+        self.inner.lock().unwrap().count
+    }
+}
+
+/// Request counters, shared by all worker threads.
+#[derive(Debug)]
+pub struct Stats {
+    started: Instant,
+    requests: AtomicU64,
+    bytes: AtomicU64,
+    busy: AtomicUsize,
+    workers: AtomicUsize,
+    scripts: AtomicU64,
+    script_failures: AtomicU64,
+    script_seconds: Histogram,
+    response_bytes: Histogram,
+    kinds: Mutex<HashMap<&'static str, u64>>,
+    selectors: Mutex<HashMap<String, u64>>,
+    clients: Mutex<HashMap<IpAddr, u64>>,
+}
+
+impl Default for Stats {
+    fn default() -> Stats {
+        Stats {
+            started: Instant::now(),
+            requests: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            busy: AtomicUsize::new(0),
+            workers: AtomicUsize::new(0),
+            scripts: AtomicU64::new(0),
+            script_failures: AtomicU64::new(0),
+            script_seconds: Histogram::new(SCRIPT_SECONDS),
+            response_bytes: Histogram::new(RESPONSE_BYTES),
+            kinds: Mutex::new(HashMap::new()),
+            selectors: Mutex::new(HashMap::new()),
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Stats {
+    /// A worker has started handling a connection.
+    pub fn begin(&self) {
+        self.busy.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A worker is done with a connection.
+    pub fn end(&self) {
+        self.busy.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Count a response. `kind` is the kind of response, e.g. `DIR` or
+    /// `NOTFOUND`.
+    pub fn record(&self, selector: &str, peer: Option<IpAddr>, kind: &'static str, bytes: u64) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.response_bytes.observe(bytes as f64);
+        bump(&self.kinds, kind);
+        bump(
+            &self.selectors,
+            if selector.is_empty() { "/" } else { selector }.to_string(),
+        );
+        if let Some(ip) = peer {
+            bump(&self.clients, ip.to_canonical());
+        }
+    }
+
+    /// Record the size of the worker pool.
+    pub fn set_workers(&self, workers: usize) {
+        self.workers.store(workers, Ordering::Relaxed);
+    }
+
+    /// Count a run of an executable `.gph` file. `ok` is false if it
+    /// couldn't be started or exited with an error.
+    pub fn script(&self, duration: Duration, ok: bool) {
+        self.scripts.fetch_add(1, Ordering::Relaxed);
+        if !ok {
+            self.script_failures.fetch_add(1, Ordering::Relaxed);
+        }
+        self.script_seconds.observe(duration.as_secs_f64());
+    }
+
+    /// Time since the server started.
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Total requests served.
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    /// Total bytes sent to clients.
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Requests being handled right now.
+    pub fn busy(&self) -> usize {
+        self.busy.load(Ordering::Relaxed)
+    }
+
+    /// Size of the worker pool.
+    pub fn workers(&self) -> usize {
+        self.workers.load(Ordering::Relaxed)
+    }
+
+    /// Scripts run.
+    pub fn scripts(&self) -> u64 {
+        self.scripts.load(Ordering::Relaxed)
+    }
+
+    /// Scripts that failed.
+    pub fn script_failures(&self) -> u64 {
+        self.script_failures.load(Ordering::Relaxed)
+    }
+
+    /// How long scripts took to run, in seconds.
+    pub fn script_seconds(&self) -> &Histogram {
+        &self.script_seconds
+    }
+
+    /// Size of each response, in bytes.
+    pub fn response_bytes(&self) -> &Histogram {
+        &self.response_bytes
+    }
+
+    /// Number of responses of each kind, sorted by kind.
+    pub fn kinds(&self) -> Vec<(&'static str, u64)> {
+        let mut kinds: Vec<_> = self.kinds.lock().unwrap().clone().into_iter().collect();
+        kinds.sort();
+        kinds
+    }
+
+    /// Most requested selectors.
+    pub fn top_selectors(&self) -> Vec<(String, u64)> {
+        top(&self.selectors)
+    }
+
+    /// Clients that have made the most requests.
+    pub fn top_clients(&self) -> Vec<(IpAddr, u64)> {
+        top(&self.clients)
+    }
+
+    /// Lines of text for the stats page.
+    pub fn report(&self) -> Vec<String> {
+        let mut lines = vec![
+            "~ phd stats ~".to_string(),
+            "".into(),
+            format!("Uptime:        {}", human_duration(self.uptime())),
+            format!("Requests:      {}", self.requests()),
+            format!("Bytes served:  {}", human_bytes(self.bytes())),
+            format!("Busy workers:  {} of {}", self.busy(), self.workers()),
+            format!(
+                "Scripts run:   {} ({} failed)",
+                self.scripts(),
+                self.script_failures()
+            ),
+            "".into(),
+            "Responses:".into(),
+        ];
+        for (kind, n) in self.kinds() {
+            lines.push(format!("  {:<10} {}", kind, n));
+        }
+        lines.push("".into());
+        lines.push("Top selectors:".into());
+        for (sel, n) in self.top_selectors() {
+            lines.push(format!("  {:<8} {}", n, sel));
+        }
+        lines.push("".into());
+        lines.push("Top clients:".into());
+        for (ip, n) in self.top_clients() {
+            lines.push(format!("  {:<8} {}", n, ip));
+        }
+        lines
+    }
+}
+
+/// Add one to a counter in a map, unless the map is full.
+fn bump<K: Eq + Hash>(map: &Mutex<HashMap<K, u64>>, key: K) {
+    let mut map = map.lock().unwrap();
+    if map.len() < MAX_TRACKED || map.contains_key(&key) {
+        *map.entry(key).or_insert(0) += 1;
+    }
+}
+
+/// The biggest counters in a map, biggest first.
+fn top<K: Clone + Ord>(map: &Mutex<HashMap<K, u64>>) -> Vec<(K, u64)> {
+    let mut list: Vec<_> = map
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(k, n)| (k.clone(), *n))
+        .collect();
+    list.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    list.truncate(TOP_N);
+    list
+}
+
+/// 90061 seconds => `1d 1h 1m 1s`
+fn human_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    let (days, hours, mins) = (secs / 86_400, secs % 86_400 / 3600, secs % 3600 / 60);
+    if days > 0 {
+        format!("{}d {}h {}m {}s", days, hours, mins, secs % 60)
+    } else if hours > 0 {
+        format!("{}h {}m {}s", hours, mins, secs % 60)
+    } else if mins > 0 {
+        format!("{}m {}s", mins, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+/// 1536 => `1.5K`
+fn human_bytes(bytes: u64) -> String {
+    let mut size = bytes as f64;
+    for unit in &["B", "K", "M", "G"] {
+        if size < 1024.0 {
+            return if *unit == "B" {
+                format!("{}B", bytes)
+            } else {
+                format!("{:.1}{}", size, unit)
+            };
+        }
+        size /= 1024.0;
+    }
+    format!("{:.1}T", size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counts() {
+        let stats = Stats::default();
+        let ip = "10.0.0.1".parse().ok();
+        for sel in &["/a", "/b", "/a", ""] {
+            stats.begin();
+            stats.record(sel, ip, "DIR", 100);
+            stats.end();
+        }
+        stats.begin();
+        stats.record("/nope", None, "NOTFOUND", 10);
+
+        assert_eq!(stats.requests(), 5);
+        assert_eq!(stats.bytes(), 410);
+        assert_eq!(stats.busy(), 1);
+        assert_eq!(stats.kinds(), vec![("DIR", 4), ("NOTFOUND", 1)]);
+        assert_eq!(stats.top_selectors()[0], ("/a".to_string(), 2));
+        assert_eq!(stats.top_selectors()[1], ("/".to_string(), 1));
+        assert_eq!(stats.top_clients(), vec![(ip.unwrap(), 4)]);
+    }
+
+    #[test]
+    fn test_histogram() {
+        let hist = Histogram::new(&[1.0, 10.0]);
+        for val in &[0.5, 1.0, 5.0, 50.0] {
+            hist.observe(*val);
+        }
+        assert_eq!(hist.buckets(), vec![(1.0, 2), (10.0, 3)]);
+        assert_eq!(hist.count(), 4);
+        assert_eq!(hist.sum(), 56.5);
+    }
+
+    #[test]
+    fn test_human() {
+        assert_eq!(human_duration(Duration::from_secs(59)), "59s");
+        assert_eq!(human_duration(Duration::from_secs(90_061)), "1d 1h 1m 1s");
+        assert_eq!(human_bytes(1000), "1000B");
+        assert_eq!(human_bytes(1536), "1.5K");
+        assert_eq!(human_bytes(5 * 1024 * 1024), "5.0M");
+    }
+}
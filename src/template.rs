@@ -0,0 +1,241 @@
+//! A small template language for gophermaps, so common dynamic bits
+//! don't need a script:
+//!
+//! ```text
+//! Welcome to {{host}}! It's {{date}}.
+//! {{include /fragments/nav.gph}}
+//! {{#each /phlog}}
+//! [0|{{date}} {{title}}|{{selector}}|server|port]
+//! {{/each}}
+//! ```
+//!
+//! Variables and directory entries come from an `Env`, which decides
+//! what's allowed. Anything in `{{ }}` we don't recognize is left as
+//! written, so existing files that happen to contain braces still work.
+
+use crate::Result;
+use std::collections::HashMap;
+
+/// Variables for one directory entry in an `{{#each}}` loop.
+pub type Vars = HashMap<String, String>;
+
+/// Where a template gets its data.
+pub trait Env {
+    /// Value of a variable like `host`, or None if there's no such
+    /// variable.
+    fn var(&self, name: &str) -> Option<String>;
+    /// Rendered contents of the file at `path`.
+    fn include(&self, path: &str) -> Result<String>;
+    /// Variables for each entry in the directory at `path`, in the
+    /// order they're listed.
+    fn entries(&self, path: &str) -> Result<Vec<Vars>>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Text(String),
+    Var(String),
+    Include(String),
+    Each { dir: String, body: Vec<Node> },
+}
+
+/// Does `text` look like it uses templates? Used to avoid caching
+/// output that can change from one request to the next.
+pub fn is_template(text: &str) -> bool {
+    text.contains("{{")
+}
+
+/// Render `text` with the data in `env`.
+pub fn render<E: Env>(text: &str, env: &E) -> Result<String> {
+    let mut rest = text;
+    let nodes = parse(&mut rest, false)?;
+    let mut out = String::new();
+    render_nodes(&nodes, env, &[], &mut out)?;
+    Ok(out)
+}
+
+/// Parse nodes until the end of `text`, or until `{{/each}}` if we're
+/// `inside` a loop.
+fn parse(text: &mut &str, inside: bool) -> Result<Vec<Node>> {
+    let mut nodes = vec![];
+    while let Some(start) = text.find("{{") {
+        let end = match text[start..].find("}}") {
+            Some(end) => start + end,
+            None => break,
+        };
+        let tag = text[start + 2..end].trim();
+        let literal = &text[start..end + 2];
+        push_text(&mut nodes, &text[..start]);
+        *text = &text[end + 2..];
+
+        if tag == "/each" {
+            if !inside {
+                return Err("{{/each}} without {{#each}}".into());
+            }
+            skip_newline(text);
+            return Ok(nodes);
+        } else if tag == "#each" || tag.starts_with("#each ") {
+            let dir = tag["#each".len()..].trim();
+            skip_newline(text);
+            let body = parse(text, true)?;
+            nodes.push(Node::Each {
+                dir: if dir.is_empty() { "." } else { dir }.into(),
+                body,
+            });
+        } else if let Some(path) = tag.strip_prefix("include ") {
+            nodes.push(Node::Include(path.trim().into()));
+        } else if !tag.is_empty() && tag.chars().all(|c| c.is_ascii_lowercase() || c == '_') {
+            nodes.push(Node::Var(tag.into()));
+        } else {
+            push_text(&mut nodes, literal);
+        }
+    }
+    if inside {
+        return Err("{{#each}} without {{/each}}".into());
+    }
+    push_text(&mut nodes, text);
+    *text = "";
+    Ok(nodes)
+}
+
+fn push_text(nodes: &mut Vec<Node>, text: &str) {
+    if text.is_empty() {
+        return;
+    }
+    if let Some(Node::Text(last)) = nodes.last_mut() {
+        last.push_str(text);
+    } else {
+        nodes.push(Node::Text(text.into()));
+    }
+}
+
+/// Loop tags on their own line shouldn't leave blank lines behind.
+fn skip_newline(text: &mut &str) {
+    if let Some(rest) = text
+        .strip_prefix("\r\n")
+        .or_else(|| text.strip_prefix('\n'))
+    {
+        *text = rest;
+    }
+}
+
+/// Render nodes, looking variables up in `scopes` (innermost last)
+/// before `env`.
+fn render_nodes<E: Env>(nodes: &[Node], env: &E, scopes: &[&Vars], out: &mut String) -> Result<()> {
+    for node in nodes {
+        match node {
+            Node::Text(text) => out.push_str(text),
+            Node::Var(name) => {
+                let val = scopes
+                    .iter()
+                    .rev()
+                    .find_map(|vars| vars.get(name).cloned())
+                    .or_else(|| env.var(name));
+                match val {
+                    Some(val) => {
+                        // A value that starts a line could turn it into a link.
+                        let line = &out[out.rfind('\n').map_or(0, |i| i + 1)..];
+                        let link = line.is_empty() || line.starts_with('[');
+                        out.push_str(&clean(&val, link));
+                    }
+                    None => {
+                        out.push_str("{{");
+                        out.push_str(name);
+                        out.push_str("}}");
+                    }
+                }
+            }
+            Node::Include(path) => {
+                let text = env.include(path)?;
+                out.push_str(text.strip_suffix('\n').unwrap_or(&text));
+            }
+            Node::Each { dir, body } => {
+                for vars in env.entries(dir)? {
+                    let mut inner = scopes.to_vec();
+                    inner.push(&vars);
+                    render_nodes(body, env, &inner, out)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Values end up in the middle of a gophermap line, so they mustn't
+/// be able to end it or start a new field. In a `[type|text|selector|
+/// host|port]` link, they can't add fields or brackets either: `|` is
+/// escaped as `\|`, like in geomyidae, and `[` and `]` are dropped.
+fn clean(val: &str, link: bool) -> String {
+    let val = val.replace(['\t', '\r', '\n'], " ");
+    if link {
+        val.replace(['[', ']'], "").replace('|', "\\|")
+    } else {
+        val
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Test;
+
+    impl Env for Test {
+        fn var(&self, name: &str) -> Option<String> {
+            match name {
+                "host" => Some("example.com".into()),
+                "date" => Some("2020-01-31".into()),
+                "query" => Some("x\tfake\thost\t70\r\n1evil|/etc|evil|66]".into()),
+                _ => None,
+            }
+        }
+
+        fn include(&self, path: &str) -> Result<String> {
+            match path {
+                "nav.gph" => Ok("[1|Home|/]\n".into()),
+                _ => Err(format!("can't include {}", path).into()),
+            }
+        }
+
+        fn entries(&self, path: &str) -> Result<Vec<Vars>> {
+            assert_eq!(path, ".");
+            Ok(["a", "b"]
+                .iter()
+                .map(|name| {
+                    let mut vars = Vars::new();
+                    vars.insert("name".into(), name.to_string());
+                    vars
+                })
+                .collect())
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let out = render(
+            "Hi {{ host }} {{nope}} {{Not A Tag}}\n{{include nav.gph}}\n\
+             {{#each}}\n- {{name}} {{date}}\n{{/each}}\nbye {{",
+            &Test,
+        )
+        .unwrap();
+        assert_eq!(
+            out,
+            "Hi example.com {{nope}} {{Not A Tag}}\n[1|Home|/]\n\
+             - a 2020-01-31\n- b 2020-01-31\nbye {{"
+        );
+        assert_eq!(
+            render("[1|{{query}}|/]", &Test).unwrap(),
+            "[1|x fake host 70  1evil\\|/etc\\|evil\\|66|/]"
+        );
+        assert_eq!(
+            render("{{query}}\nYou said {{query}}", &Test).unwrap(),
+            "x fake host 70  1evil\\|/etc\\|evil\\|66\n\
+             You said x fake host 70  1evil|/etc|evil|66]"
+        );
+        assert!(render("{{include other.gph}}", &Test).is_err());
+        assert!(render("{{#each}} x", &Test).is_err());
+        assert!(render("x {{/each}}", &Test).is_err());
+        assert!(is_template("{{host}}"));
+        assert!(!is_template("plain"));
+    }
+}
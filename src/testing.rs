@@ -0,0 +1,80 @@
+//! Helpers shared by the unit tests.
+
+use crate::Config;
+use std::{
+    env, fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Tells apart directories made with the same name in one test run.
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// A scratch directory under the system temp dir. It's removed when
+/// dropped, so a failed assertion doesn't leave fixtures behind.
+pub(crate) struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// Make an empty directory. `name` shows up in the path to help
+    /// with debugging, but it's unique even if two tests share a name.
+    pub(crate) fn new(name: &str) -> TempDir {
+        let n = COUNT.fetch_add(1, Ordering::Relaxed);
+        let path = env::temp_dir().join(format!("phd-{}-{}-{}", name, process::id(), n));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir {
+            path: fs::canonicalize(&path).unwrap(),
+        }
+    }
+
+    /// Where the directory is.
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Path to `rel` inside the directory.
+    pub(crate) fn join<P: AsRef<Path>>(&self, rel: P) -> PathBuf {
+        self.path.join(rel)
+    }
+
+    /// Make the directory `rel` and any parents.
+    pub(crate) fn mkdir(&self, rel: &str) -> PathBuf {
+        let path = self.join(rel);
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    /// Write `contents` to the file `rel`, making its directory first.
+    pub(crate) fn write<C: AsRef<[u8]>>(&self, rel: &str, contents: C) -> PathBuf {
+        let path = self.join(rel);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).unwrap();
+        }
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    /// Write an executable script to `rel`.
+    pub(crate) fn script(&self, rel: &str, contents: &str) -> PathBuf {
+        let path = self.write(rel, contents);
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    /// The default config, serving this directory.
+    pub(crate) fn config(&self) -> Config {
+        let mut config = Config::default();
+        config.root = self.path.to_string_lossy().into();
+        config
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
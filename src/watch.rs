@@ -0,0 +1,388 @@
+//! Watch the site for changes, so anything built from its files (like
+//! cached menus) can be thrown away as soon as an author edits them.
+//!
+//! On Linux we ask the kernel with inotify. Elsewhere, or if inotify
+//! isn't available or runs out of watches, we walk the whole tree
+//! every `watch-interval` seconds and compare what we find.
+
+use crate::{color, log::Logger, Result};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+/// Something in the site changed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// A file or directory was created, modified, removed, or renamed.
+    Changed(PathBuf),
+    /// We may have missed changes, so anything could be different.
+    Rescan,
+}
+
+type Subscriber = Box<dyn Fn(&Event) + Send>;
+
+/// Keeps a watcher running. Dropping it stops the watcher, e.g. when
+/// the site moves somewhere else.
+#[must_use = "the watcher stops when its handle is dropped"]
+pub struct Handle {
+    _stop: Sender<()>,
+}
+
+/// Publishes changes under a directory to its subscribers.
+pub struct Watcher {
+    root: PathBuf,
+    interval: Duration,
+    subscribers: Vec<Subscriber>,
+}
+
+impl Watcher {
+    /// Watch everything under `root`. `interval` is how often to look
+    /// for changes if we have to poll.
+    pub fn new<P: AsRef<Path>>(root: P, interval: Duration) -> Watcher {
+        Watcher {
+            root: root.as_ref().into(),
+            interval,
+            subscribers: vec![],
+        }
+    }
+
+    /// Call `f` with every change.
+    pub fn subscribe<F: Fn(&Event) + Send + 'static>(&mut self, f: F) {
+        self.subscribers.push(Box::new(f));
+    }
+
+    /// Start watching in the background. Runs until the returned
+    /// handle is dropped.
+    pub fn spawn(self, log: Logger) -> Result<Handle> {
+        let (tx, rx) = mpsc::channel();
+        thread::Builder::new()
+            .name("watch".into())
+            .spawn(move || self.run(log, rx))?;
+        Ok(Handle { _stop: tx })
+    }
+
+    fn run(self, log: Logger, stop: Receiver<()>) {
+        #[cfg(target_os = "linux")]
+        {
+            match inotify::Inotify::new(&self.root) {
+                Ok(inotify) => {
+                    debug!(
+                        log,
+                        "» Watching {} with inotify ({} directories)",
+                        self.root.display(),
+                        inotify.len()
+                    );
+                    let stopped = || stopped(&stop);
+                    match inotify.run(|event| self.publish(&event), stopped) {
+                        Ok(()) => return,
+                        Err(e) => warn!(log, "{}» inotify: {}{}", color::Red, e, color::Reset),
+                    }
+                }
+                Err(e) => warn!(log, "{}» inotify: {}{}", color::Red, e, color::Reset),
+            }
+        }
+        debug!(
+            log,
+            "» Watching {} by checking every {}s",
+            self.root.display(),
+            self.interval.as_secs()
+        );
+        self.publish(&Event::Rescan);
+        self.poll(&stop);
+    }
+
+    fn publish(&self, event: &Event) {
+        for subscriber in &self.subscribers {
+            subscriber(event);
+        }
+    }
+
+    /// Walk the tree every `interval`, reporting what's different,
+    /// until we're told to stop.
+    fn poll(&self, stop: &Receiver<()>) {
+        let mut before = snapshot(&self.root);
+        while let Err(RecvTimeoutError::Timeout) = stop.recv_timeout(self.interval) {
+            let after = snapshot(&self.root);
+            for change in diff(&before, &after) {
+                self.publish(&Event::Changed(change));
+            }
+            before = after;
+        }
+    }
+}
+
+/// Has our handle been dropped? Nothing is ever sent, so the channel
+/// only tells us when it's disconnected.
+fn stopped(stop: &Receiver<()>) -> bool {
+    matches!(stop.try_recv(), Err(TryRecvError::Disconnected))
+}
+
+/// Modification time and size of everything under a directory.
+type Snapshot = HashMap<PathBuf, (Option<SystemTime>, u64)>;
+
+fn snapshot(root: &Path) -> Snapshot {
+    let mut files = HashMap::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir).into_iter().flatten().flatten() {
+            let meta = match entry.metadata() {
+                Ok(meta) => meta,
+                Err(_) => continue,
+            };
+            // Don't follow symlinks, so loops can't trap us.
+            if meta.is_dir() {
+                dirs.push(entry.path());
+            }
+            files.insert(entry.path(), (meta.modified().ok(), meta.len()));
+        }
+        if let Ok(meta) = fs::metadata(&dir) {
+            files.insert(dir, (meta.modified().ok(), meta.len()));
+        }
+    }
+    files
+}
+
+/// Paths that were added, removed, or changed between two snapshots.
+fn diff(before: &Snapshot, after: &Snapshot) -> Vec<PathBuf> {
+    let mut changes: Vec<PathBuf> = after
+        .iter()
+        .filter(|(path, stamp)| before.get(*path) != Some(stamp))
+        .map(|(path, _)| path.clone())
+        .collect();
+    changes.extend(
+        before
+            .keys()
+            .filter(|path| !after.contains_key(*path))
+            .cloned(),
+    );
+    changes.sort();
+    changes
+}
+
+#[cfg(target_os = "linux")]
+mod inotify {
+    use super::Event;
+    use std::{
+        collections::HashMap,
+        ffi::{CString, OsStr},
+        fs, io, mem,
+        os::unix::ffi::{OsStrExt, OsStringExt},
+        path::{Path, PathBuf},
+    };
+
+    /// How often to check whether we should stop, in milliseconds.
+    const STOP_CHECK_MS: libc::c_int = 200;
+
+    /// Everything that can change what we'd serve.
+    const MASK: u32 = libc::IN_CREATE
+        | libc::IN_DELETE
+        | libc::IN_MODIFY
+        | libc::IN_ATTRIB
+        | libc::IN_MOVED_FROM
+        | libc::IN_MOVED_TO
+        | libc::IN_DELETE_SELF
+        | libc::IN_ONLYDIR;
+
+    /// An inotify instance watching every directory under a root.
+    pub(super) struct Inotify {
+        fd: libc::c_int,
+        dirs: HashMap<libc::c_int, PathBuf>,
+    }
+
+    impl Drop for Inotify {
+        fn drop(&mut self) {
+            unsafe { libc::close(self.fd) };
+        }
+    }
+
+    impl Inotify {
+        pub(super) fn new(root: &Path) -> io::Result<Inotify> {
+            let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut inotify = Inotify {
+                fd,
+                dirs: HashMap::new(),
+            };
+            inotify.add_tree(root)?;
+            Ok(inotify)
+        }
+
+        /// Number of directories being watched.
+        pub(super) fn len(&self) -> usize {
+            self.dirs.len()
+        }
+
+        /// Watch `dir` and every directory under it.
+        fn add_tree(&mut self, dir: &Path) -> io::Result<()> {
+            let mut todo = vec![dir.to_path_buf()];
+            while let Some(dir) = todo.pop() {
+                let path = CString::new(dir.clone().into_os_string().into_vec())?;
+                let wd = unsafe { libc::inotify_add_watch(self.fd, path.as_ptr(), MASK) };
+                if wd < 0 {
+                    let err = io::Error::last_os_error();
+                    match err.raw_os_error() {
+                        // It went away, or isn't a directory after all.
+                        Some(libc::ENOENT) | Some(libc::ENOTDIR) => continue,
+                        _ => return Err(err),
+                    }
+                }
+                for entry in fs::read_dir(&dir).into_iter().flatten().flatten() {
+                    if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                        todo.push(entry.path());
+                    }
+                }
+                self.dirs.insert(wd, dir);
+            }
+            Ok(())
+        }
+
+        /// Report changes until `stopped` says so, or something goes
+        /// wrong.
+        pub(super) fn run<F, S>(mut self, publish: F, stopped: S) -> io::Result<()>
+        where
+            F: Fn(Event),
+            S: Fn() -> bool,
+        {
+            let mut buf = vec![0u8; 64 * 1024];
+            loop {
+                if stopped() {
+                    return Ok(());
+                }
+                let mut fds = libc::pollfd {
+                    fd: self.fd,
+                    events: libc::POLLIN,
+                    revents: 0,
+                };
+                match unsafe { libc::poll(&mut fds, 1, STOP_CHECK_MS) } {
+                    -1 => {
+                        let err = io::Error::last_os_error();
+                        if err.kind() == io::ErrorKind::Interrupted {
+                            continue;
+                        }
+                        return Err(err);
+                    }
+                    0 => continue,
+                    _ => {}
+                }
+                let n = unsafe { libc::read(self.fd, buf.as_mut_ptr().cast(), buf.len()) };
+                if n < 0 {
+                    let err = io::Error::last_os_error();
+                    if err.kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    return Err(err);
+                }
+                let mut events = vec![];
+                let mut pos = 0;
+                let header = mem::size_of::<libc::inotify_event>();
+                while pos + header <= n as usize {
+                    let event: libc::inotify_event =
+                        unsafe { std::ptr::read_unaligned(buf[pos..].as_ptr().cast()) };
+                    let name = &buf[pos + header..pos + header + event.len as usize];
+                    let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+                    events.push((event.wd, event.mask, OsStr::from_bytes(name).to_owned()));
+                    pos += header + event.len as usize;
+                }
+
+                for (wd, mask, name) in events {
+                    if mask & libc::IN_Q_OVERFLOW != 0 {
+                        publish(Event::Rescan);
+                        continue;
+                    }
+                    if mask & libc::IN_IGNORED != 0 {
+                        self.dirs.remove(&wd);
+                        continue;
+                    }
+                    let dir = match self.dirs.get(&wd) {
+                        Some(dir) => dir.clone(),
+                        None => continue,
+                    };
+                    let path = if name.is_empty() { dir } else { dir.join(name) };
+                    let new_dir = libc::IN_CREATE | libc::IN_MOVED_TO;
+                    if mask & libc::IN_ISDIR != 0 && mask & new_dir != 0 {
+                        // Files may have landed in it before we were
+                        // watching, so it counts as a change too.
+                        self.add_tree(&path)?;
+                    }
+                    publish(Event::Changed(path));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use std::{sync::mpsc, time::Instant};
+
+    /// Wait for an event about `path`.
+    fn expect(rx: &mpsc::Receiver<Event>, path: &Path) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            match rx.recv_timeout(left) {
+                Ok(Event::Changed(p)) if p == path => return,
+                Ok(_) => continue,
+                Err(_) => break,
+            }
+        }
+        panic!("no event for {}", path.display());
+    }
+
+    #[test]
+    fn test_watch() {
+        let tmp = TempDir::new("watch");
+        tmp.mkdir("sub");
+        let root = tmp.path();
+        let mut watcher = Watcher::new(root, Duration::from_millis(100));
+        let (tx, rx) = mpsc::channel();
+        watcher.subscribe(move |event| {
+            let _ = tx.send(event.clone());
+        });
+        let handle = watcher.spawn(Logger::silent()).unwrap();
+        thread::sleep(Duration::from_millis(200));
+
+        fs::write(root.join("sub/a.txt"), "a").unwrap();
+        expect(&rx, &root.join("sub/a.txt"));
+        fs::create_dir(root.join("new")).unwrap();
+        expect(&rx, &root.join("new"));
+        thread::sleep(Duration::from_millis(200));
+        fs::write(root.join("new/b.txt"), "b").unwrap();
+        expect(&rx, &root.join("new/b.txt"));
+
+        // Dropping the handle stops the watcher, taking its
+        // subscribers with it.
+        drop(handle);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            match rx.recv_timeout(Duration::from_millis(100)) {
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                _ => assert!(Instant::now() < deadline, "watcher didn't stop"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        let t = SystemTime::UNIX_EPOCH;
+        let mut before = Snapshot::new();
+        before.insert("/a".into(), (Some(t), 1));
+        before.insert("/b".into(), (Some(t), 1));
+        let mut after = before.clone();
+        after.remove(Path::new("/a"));
+        after.insert("/b".into(), (Some(t), 2));
+        after.insert("/c".into(), (Some(t), 1));
+        assert_eq!(
+            diff(&before, &after),
+            vec![PathBuf::from("/a"), "/b".into(), "/c".into()]
+        );
+    }
+}